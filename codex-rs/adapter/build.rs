@@ -1,4 +1,8 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tonic_build::compile_protos("proto/adapter.proto")?;
+    // 描述符集供 reflection feature 下的 gRPC 反射服务使用
+    let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR")?);
+    tonic_build::configure()
+        .file_descriptor_set_path(out_dir.join("adapter_descriptor.bin"))
+        .compile(&["proto/adapter.proto"], &["proto"])?;
     Ok(())
 }
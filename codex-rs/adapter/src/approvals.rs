@@ -0,0 +1,17 @@
+use crate::agent::ApprovalPolicy;
+
+/// 需要客户端参与审批的策略 (proto 中已弃用)；codex exec 固定以 never 运行，无法支持这些策略
+pub fn is_interactive(policy: ApprovalPolicy) -> bool {
+    matches!(policy, ApprovalPolicy::Always | ApprovalPolicy::UnlessTrusted | ApprovalPolicy::OnRequest | ApprovalPolicy::OnFailure)
+}
+
+/// codex `approval_policy` 配置值
+pub fn policy_value(policy: ApprovalPolicy) -> Option<&'static str> {
+    match policy {
+        ApprovalPolicy::Unspecified => None,
+        ApprovalPolicy::Always | ApprovalPolicy::UnlessTrusted => Some("untrusted"),
+        ApprovalPolicy::OnRequest => Some("on-request"),
+        ApprovalPolicy::OnFailure => Some("on-failure"),
+        ApprovalPolicy::Never => Some("never"),
+    }
+}
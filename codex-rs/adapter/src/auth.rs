@@ -0,0 +1,44 @@
+use tonic::{Request, Status};
+use tonic::metadata::MetadataMap;
+
+// 管理类 RPC 通过该 metadata 携带管理员令牌
+pub const ADMIN_TOKEN_HEADER: &str = "x-admin-token";
+
+/// 校验管理员令牌；服务端未配置 ADMIN_TOKEN 时管理接口整体禁用
+pub fn check_admin(metadata: &MetadataMap, admin_token: Option<&str>) -> Result<(), Status> {
+    let Some(expected) = admin_token else {
+        return Err(Status::permission_denied("admin endpoints are disabled: ADMIN_TOKEN is not configured"));
+    };
+    let provided = metadata.get(ADMIN_TOKEN_HEADER).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        Err(Status::permission_denied("invalid admin token"))
+    }
+}
+
+/// 校验 `authorization: Bearer <token>`；未配置 AGENT_SERVER_TOKEN 时不做鉴权
+pub fn bearer_interceptor(token: Option<String>) -> impl FnMut(Request<()>) -> Result<Request<()>, Status> + Clone {
+    move |request: Request<()>| {
+        let Some(expected) = &token else { return Ok(request) };
+        let provided = request
+            .metadata()
+            .get("authorization")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .unwrap_or_default();
+        if constant_time_eq(provided.as_bytes(), expected.as_bytes()) {
+            Ok(request)
+        } else {
+            Err(Status::unauthenticated("missing or invalid bearer token"))
+        }
+    }
+}
+
+/// 常量时间比较，避免通过响应耗时逐字节猜测令牌
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
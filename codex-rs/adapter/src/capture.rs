@@ -0,0 +1,125 @@
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, BufWriter};
+
+use crate::agent::run_task_response::Event;
+use crate::error::{AdapterError, map_io_error};
+use crate::{EventSender, event_response};
+
+/// codex stdout 事件的缓冲方式，由 RunTaskRequest.output_capture_mode 选择
+#[tonic::async_trait]
+pub trait CaptureStrategy: Send {
+    /// 处理一行 stdout，event 为调用方已解析出的 JSON (无法解析时为 None)；返回 false 表示客户端已断开
+    async fn on_line(&mut self, line: String, event: Option<&serde_json::Value>) -> Result<bool, AdapterError>;
+
+    /// 子进程 stdout 结束后调用；返回 false 表示客户端已断开
+    async fn finish(&mut self) -> Result<bool, AdapterError>;
+}
+
+pub const CAPTURE_MODES: &[&str] = &["stream", "file", "tee"];
+
+/// typed_events 为 true 时可识别的事件以结构化事件发送 (见 RunTaskRequest.typed_events)
+pub async fn capture_strategy(mode: &str, tx: EventSender, codex_home: &Path, typed_events: bool) -> Result<Box<dyn CaptureStrategy>, AdapterError> {
+    let path = codex_home.join("captured-events.jsonl");
+    Ok(match mode {
+        "file" => Box::new(FileCapture { file: CaptureFile::create(path).await?, tx, typed_events }),
+        "tee" => Box::new(TeeCapture { file: CaptureFile::create(path).await?, stream: StreamCapture { tx, typed_events } }),
+        _ => Box::new(StreamCapture { tx, typed_events }),
+    })
+}
+
+// 一行 codex stdout 对应的事件；无法识别或解析的行原样作为 codex_event_json
+fn codex_event(line: String, event: Option<&serde_json::Value>, typed_events: bool) -> Event {
+    if typed_events
+        && let Some(typed) = event.and_then(crate::events::typed_event)
+    {
+        return typed;
+    }
+    Event::CodexEventJson(line)
+}
+
+/// 默认：逐行实时转发
+pub struct StreamCapture {
+    tx: EventSender,
+    typed_events: bool,
+}
+
+#[tonic::async_trait]
+impl CaptureStrategy for StreamCapture {
+    async fn on_line(&mut self, line: String, event: Option<&serde_json::Value>) -> Result<bool, AdapterError> {
+        Ok(self.tx.send(Ok(event_response(codex_event(line, event, self.typed_events)))).await.is_ok())
+    }
+
+    async fn finish(&mut self) -> Result<bool, AdapterError> {
+        Ok(!self.tx.is_closed())
+    }
+}
+
+/// 先写入临时文件，进程退出后一次性回放 (适合非流式的批处理客户端)
+pub struct FileCapture {
+    file: CaptureFile,
+    tx: EventSender,
+    typed_events: bool,
+}
+
+#[tonic::async_trait]
+impl CaptureStrategy for FileCapture {
+    async fn on_line(&mut self, line: String, _event: Option<&serde_json::Value>) -> Result<bool, AdapterError> {
+        self.file.append(&line).await?;
+        Ok(!self.tx.is_closed())
+    }
+
+    async fn finish(&mut self) -> Result<bool, AdapterError> {
+        self.file.flush().await?;
+        let file = tokio::fs::File::open(&self.file.path).await.map_err(|e| map_io_error(e, &self.file.path))?;
+        let mut lines = BufReader::new(file).lines();
+        while let Some(line) = lines.next_line().await? {
+            // 解析结果未随行落盘，回放时仅在需要结构化事件时重新解析
+            let event = if self.typed_events { serde_json::from_str::<serde_json::Value>(&line).ok() } else { None };
+            if self.tx.send(Ok(event_response(codex_event(line, event.as_ref(), self.typed_events)))).await.is_err() {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// 实时转发的同时写入文件
+pub struct TeeCapture {
+    file: CaptureFile,
+    stream: StreamCapture,
+}
+
+#[tonic::async_trait]
+impl CaptureStrategy for TeeCapture {
+    async fn on_line(&mut self, line: String, event: Option<&serde_json::Value>) -> Result<bool, AdapterError> {
+        self.file.append(&line).await?;
+        self.stream.on_line(line, event).await
+    }
+
+    async fn finish(&mut self) -> Result<bool, AdapterError> {
+        self.file.flush().await?;
+        self.stream.finish().await
+    }
+}
+
+struct CaptureFile {
+    path: PathBuf,
+    writer: BufWriter<tokio::fs::File>,
+}
+
+impl CaptureFile {
+    async fn create(path: PathBuf) -> Result<Self, AdapterError> {
+        let file = tokio::fs::File::create(&path).await.map_err(|e| map_io_error(e, &path))?;
+        Ok(Self { path, writer: BufWriter::new(file) })
+    }
+
+    async fn append(&mut self, line: &str) -> Result<(), AdapterError> {
+        self.writer.write_all(line.as_bytes()).await.map_err(|e| map_io_error(e, &self.path))?;
+        self.writer.write_all(b"\n").await.map_err(|e| map_io_error(e, &self.path))
+    }
+
+    async fn flush(&mut self) -> Result<(), AdapterError> {
+        self.writer.flush().await.map_err(|e| map_io_error(e, &self.path))
+    }
+}
@@ -0,0 +1,186 @@
+use std::path::PathBuf;
+
+/// 服务端配置 (启动时从环境变量读取一次)
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// gRPC 监听地址 (AGENT_SERVER_ADDR)，在 main 中解析为 SocketAddr
+    pub listen_addr: String,
+    /// codex 可执行文件 (CODEX_BIN)，可为 PATH 中的名称或完整路径
+    pub codex_bin: PathBuf,
+    /// 复活会话前校验 history_rollout 是否为合法 JSONL
+    pub validate_rollout_on_resume: bool,
+    /// 所有活跃会话的上下文文件总字节上限 (None 表示不限制)
+    pub max_total_workspace_bytes: Option<u64>,
+    /// 单个 gRPC 请求消息的最大字节数，超出时在解码前拒绝
+    pub max_request_bytes: usize,
+    /// 命名配置预设所在目录 ({CODEX_HOME}/profiles)
+    pub profiles_dir: Option<PathBuf>,
+    /// 过期持久化工作区的 GC 扫描间隔
+    pub workspace_gc_interval_secs: u64,
+    /// 共享工作区快照的根目录 ({root}/{shared_workspace_id})
+    pub shared_workspace_root: Option<PathBuf>,
+    /// 可设置 workspace_persistence_ttl_secs 的 base_dir 所在根目录 (PERSISTENT_WORKSPACE_ROOT)；
+    /// GC 只删除该目录下的工作区，未设置时拒绝 TTL 持久化
+    pub persistent_workspace_root: Option<PathBuf>,
+    /// 所有 RPC 的 Bearer 令牌 (AGENT_SERVER_TOKEN，未设置时不鉴权)
+    pub server_token: Option<String>,
+    /// 管理类 RPC 的访问令牌 (未设置时禁用管理接口)
+    pub admin_token: Option<String>,
+    /// 调试用：以 strace 包裹 codex 子进程 (ENABLE_STRACE_DEBUG)
+    pub enable_strace_debug: bool,
+    /// strace 日志目录，每个会话一个文件
+    pub strace_log_dir: PathBuf,
+    /// 按请求渲染的 CODEX_HOME 路径模板，支持 {session_id} / {tenant_id} / {date}
+    pub codex_home_template: Option<String>,
+    /// 渲染结果必须位于该目录之内
+    pub codex_home_root: Option<PathBuf>,
+    /// 单个任务 codex 标准输出的累计字节上限 (None 表示不限制)
+    pub max_subprocess_stdout_bytes: Option<u64>,
+    /// 请求 env_vars 未携带时，从服务端环境补充 OPENAI_API_KEY
+    pub auto_inject_openai_api_key: bool,
+    /// 请求 env_vars 未携带时，从服务端环境补充 ANTHROPIC_API_KEY
+    pub auto_inject_anthropic_api_key: bool,
+    /// ListTaskRegistry 保留的最近结束任务数
+    pub task_registry_history: usize,
+    /// 请求未设置 timeout_sec 时的任务总超时 (None 表示不限制)
+    pub default_task_timeout_secs: Option<u64>,
+    /// 收到 SIGTERM/SIGINT 后等待活跃任务结束的秒数，超时后终止剩余 codex 进程
+    pub shutdown_grace_secs: u64,
+    /// 流中无事件时发送心跳的间隔秒数 (0 表示关闭)
+    pub heartbeat_interval_secs: u64,
+    /// 同时运行的 codex 任务上限，超出时 RunTask 返回 RESOURCE_EXHAUSTED
+    pub max_concurrent_tasks: usize,
+    /// gRPC 监听端口的 TLS 证书与私钥 (PEM)，须同时设置；均未设置时使用明文
+    pub tls_cert_path: Option<PathBuf>,
+    pub tls_key_path: Option<PathBuf>,
+    /// codex 以清空的环境启动，仅透传这些宿主环境变量 (CODEX_ENV_PASSTHROUGH，逗号分隔)
+    pub env_passthrough: Vec<String>,
+    /// 单个请求全部 context_files 内容的字节上限
+    pub max_context_bytes: u64,
+    /// 单个 context_file 内容的字节上限
+    pub max_context_file_bytes: u64,
+    /// 设置后每个会话的 codex 输出写入 `<dir>/<session_id>.log`
+    pub log_dir: Option<PathBuf>,
+    /// 未配置 CODEX_HOME_TEMPLATE 时临时 codex home 的创建位置 (AGENT_SCRATCH_ROOT)
+    pub scratch_root: PathBuf,
+    /// 任务结束后保留临时 codex home 而非删除
+    pub keep_scratch: bool,
+    /// Prometheus 导出端监听地址 (METRICS_ADDR)，为空时不启动
+    pub metrics_addr: String,
+    /// codex 非零退出时，STDERR 命中该正则 (TRANSIENT_ERROR_PATTERN) 视为可重试的临时错误
+    pub transient_error_pattern: String,
+    /// 重试的初始退避毫秒数，每次重试翻倍
+    pub retry_backoff_base_ms: u64,
+    /// 每个任务转发给客户端的 STDERR 行数 / 字节数上限 (0 表示不限制)，超出后丢弃并提示一次
+    pub max_stderr_lines: u64,
+    pub max_stderr_bytes: u64,
+    /// RunTask 事件流的缓冲容量 (EVENT_CHANNEL_CAP)；缓冲满时默认等待客户端读取
+    pub event_channel_cap: usize,
+    /// CancelTask 发送 SIGTERM 后等待 codex 退出的秒数，超时后 SIGKILL (CANCEL_GRACE_SECS)
+    pub cancel_grace_secs: u64,
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let defaults = Self::default();
+        Self {
+            listen_addr: std::env::var("AGENT_SERVER_ADDR").ok().filter(|a| !a.trim().is_empty()).unwrap_or(defaults.listen_addr),
+            codex_bin: std::env::var_os("CODEX_BIN").filter(|v| !v.is_empty()).map_or(defaults.codex_bin, PathBuf::from),
+            validate_rollout_on_resume: env_flag("VALIDATE_ROLLOUT_ON_RESUME", defaults.validate_rollout_on_resume),
+            max_total_workspace_bytes: env_u64("MAX_TOTAL_WORKSPACE_BYTES"),
+            max_request_bytes: env_u64("MAX_REQUEST_BYTES").map_or(defaults.max_request_bytes, |v| v as usize),
+            profiles_dir: std::env::var_os("CODEX_HOME").map(|home| PathBuf::from(home).join("profiles")),
+            workspace_gc_interval_secs: env_u64("WORKSPACE_GC_INTERVAL_SECS").unwrap_or(defaults.workspace_gc_interval_secs),
+            shared_workspace_root: std::env::var_os("SHARED_WORKSPACE_ROOT").map(PathBuf::from),
+            persistent_workspace_root: std::env::var_os("PERSISTENT_WORKSPACE_ROOT").filter(|v| !v.is_empty()).map(PathBuf::from),
+            server_token: std::env::var("AGENT_SERVER_TOKEN").ok().filter(|t| !t.is_empty()),
+            admin_token: std::env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            enable_strace_debug: env_flag("ENABLE_STRACE_DEBUG", defaults.enable_strace_debug),
+            strace_log_dir: std::env::var_os("STRACE_LOG_DIR").map_or(defaults.strace_log_dir, PathBuf::from),
+            codex_home_template: std::env::var("CODEX_HOME_TEMPLATE").ok().filter(|t| !t.is_empty()),
+            codex_home_root: std::env::var_os("CODEX_HOME_ROOT").map(PathBuf::from),
+            max_subprocess_stdout_bytes: env_u64("MAX_SUBPROCESS_STDOUT_BYTES").filter(|v| *v > 0),
+            auto_inject_openai_api_key: env_flag("AUTO_INJECT_OPENAI_API_KEY", defaults.auto_inject_openai_api_key),
+            auto_inject_anthropic_api_key: env_flag("AUTO_INJECT_ANTHROPIC_API_KEY", defaults.auto_inject_anthropic_api_key),
+            task_registry_history: env_u64("TASK_REGISTRY_HISTORY").map_or(defaults.task_registry_history, |v| v as usize),
+            default_task_timeout_secs: env_u64("DEFAULT_TASK_TIMEOUT_SECS").filter(|v| *v > 0),
+            shutdown_grace_secs: env_u64("SHUTDOWN_GRACE_SEC").unwrap_or(defaults.shutdown_grace_secs),
+            heartbeat_interval_secs: env_u64("HEARTBEAT_INTERVAL_SECS").unwrap_or(defaults.heartbeat_interval_secs),
+            max_concurrent_tasks: env_u64("MAX_CONCURRENT_TASKS").map_or(defaults.max_concurrent_tasks, |v| v as usize),
+            tls_cert_path: std::env::var_os("AGENT_SERVER_TLS_CERT").filter(|v| !v.is_empty()).map(PathBuf::from),
+            tls_key_path: std::env::var_os("AGENT_SERVER_TLS_KEY").filter(|v| !v.is_empty()).map(PathBuf::from),
+            env_passthrough: std::env::var("CODEX_ENV_PASSTHROUGH").map_or(defaults.env_passthrough, |v| {
+                v.split(',').map(str::trim).filter(|name| !name.is_empty()).map(str::to_string).collect()
+            }),
+            max_context_bytes: env_u64("MAX_CONTEXT_BYTES").unwrap_or(defaults.max_context_bytes),
+            max_context_file_bytes: env_u64("MAX_CONTEXT_FILE_BYTES").unwrap_or(defaults.max_context_file_bytes),
+            log_dir: std::env::var_os("AGENT_LOG_DIR").filter(|v| !v.is_empty()).map(PathBuf::from),
+            scratch_root: std::env::var_os("AGENT_SCRATCH_ROOT").filter(|v| !v.is_empty()).map_or(defaults.scratch_root, PathBuf::from),
+            keep_scratch: env_flag("KEEP_SCRATCH", defaults.keep_scratch),
+            metrics_addr: std::env::var("METRICS_ADDR").unwrap_or(defaults.metrics_addr),
+            transient_error_pattern: std::env::var("TRANSIENT_ERROR_PATTERN").ok().filter(|p| !p.trim().is_empty()).unwrap_or(defaults.transient_error_pattern),
+            retry_backoff_base_ms: env_u64("RETRY_BACKOFF_BASE_MS").unwrap_or(defaults.retry_backoff_base_ms),
+            max_stderr_lines: env_u64("MAX_STDERR_LINES").unwrap_or(defaults.max_stderr_lines),
+            max_stderr_bytes: env_u64("MAX_STDERR_BYTES").unwrap_or(defaults.max_stderr_bytes),
+            event_channel_cap: env_u64("EVENT_CHANNEL_CAP").filter(|v| *v > 0).map_or(defaults.event_channel_cap, |v| v as usize),
+            cancel_grace_secs: env_u64("CANCEL_GRACE_SECS").unwrap_or(defaults.cancel_grace_secs),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: "0.0.0.0:50051".to_string(),
+            codex_bin: PathBuf::from("codex"),
+            validate_rollout_on_resume: true,
+            max_total_workspace_bytes: None,
+            max_request_bytes: 32 * 1024 * 1024,
+            profiles_dir: None,
+            workspace_gc_interval_secs: 300,
+            shared_workspace_root: None,
+            persistent_workspace_root: None,
+            server_token: None,
+            admin_token: None,
+            enable_strace_debug: false,
+            strace_log_dir: std::env::temp_dir().join("codex-adapter-strace"),
+            codex_home_template: None,
+            codex_home_root: None,
+            max_subprocess_stdout_bytes: None,
+            auto_inject_openai_api_key: true,
+            auto_inject_anthropic_api_key: true,
+            task_registry_history: 1000,
+            default_task_timeout_secs: None,
+            shutdown_grace_secs: 30,
+            heartbeat_interval_secs: 15,
+            max_concurrent_tasks: 8,
+            tls_cert_path: None,
+            tls_key_path: None,
+            // codex 及其调用的工具运行所需的最小集合
+            env_passthrough: ["PATH", "HOME", "USER", "LANG", "TMPDIR", "TERM"].map(str::to_string).to_vec(),
+            max_context_bytes: 64 * 1024 * 1024,
+            max_context_file_bytes: 16 * 1024 * 1024,
+            log_dir: None,
+            scratch_root: std::env::temp_dir(),
+            keep_scratch: false,
+            metrics_addr: "0.0.0.0:9090".to_string(),
+            transient_error_pattern: r"(?i)\b(429|502|503)\b|rate.?limit|overloaded|temporarily unavailable|service unavailable".to_string(),
+            retry_backoff_base_ms: 1000,
+            max_stderr_lines: 10_000,
+            max_stderr_bytes: 1024 * 1024,
+            event_channel_cap: 100,
+            cancel_grace_secs: 10,
+        }
+    }
+}
+
+fn env_flag(name: &str, default: bool) -> bool {
+    match std::env::var(name) {
+        Ok(v) => matches!(v.trim().to_ascii_lowercase().as_str(), "1" | "true" | "yes" | "on"),
+        Err(_) => default,
+    }
+}
+
+fn env_u64(name: &str) -> Option<u64> {
+    std::env::var(name).ok().and_then(|v| v.trim().parse().ok())
+}
@@ -0,0 +1,46 @@
+use std::hash::Hasher;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+
+use lru::LruCache;
+use prost::Message;
+use rustc_hash::FxHasher;
+
+use crate::agent::SessionConfig;
+use crate::error::AdapterError;
+
+/// generate_config_toml 的结果缓存，键为序列化后 SessionConfig 的 FxHash。
+/// 命中时同时比对序列化字节，哈希碰撞不会把其他会话的配置 (含 bearer token) 写入本会话。
+pub struct ConfigCache {
+    entries: Mutex<LruCache<u64, (Vec<u8>, String)>>,
+}
+
+impl ConfigCache {
+    pub fn new(capacity: NonZeroUsize) -> Self {
+        Self { entries: Mutex::new(LruCache::new(capacity)) }
+    }
+
+    pub fn get_or_generate(
+        &self,
+        config: &SessionConfig,
+        generate: impl FnOnce(&SessionConfig) -> Result<String, AdapterError>,
+    ) -> Result<String, AdapterError> {
+        let encoded = config.encode_to_vec();
+        let mut hasher = FxHasher::default();
+        hasher.write(&encoded);
+        let key = hasher.finish();
+
+        if let Ok(mut entries) = self.entries.lock()
+            && let Some((cached_bytes, toml)) = entries.get(&key)
+            && *cached_bytes == encoded
+        {
+            return Ok(toml.clone());
+        }
+
+        let toml = generate(config)?;
+        if let Ok(mut entries) = self.entries.lock() {
+            entries.put(key, (encoded, toml.clone()));
+        }
+        Ok(toml)
+    }
+}
@@ -0,0 +1,142 @@
+use std::collections::BTreeMap;
+
+use serde::Serialize;
+
+use crate::agent::{HistoryPersistence, McpServerDef, ModelProviderInfo, SessionConfig, WireApi};
+use crate::error::AdapterError;
+
+/// 写入 codex home 的 config.toml，字段与 codex ConfigToml 对齐。
+/// 由 toml crate 负责转义与键名引号，标量字段须位于表之前。
+#[derive(Debug, Serialize)]
+struct ConfigToml<'a> {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model_auto_compact_token_limit: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model_provider: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model_reasoning_effort: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model_verbosity: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instructions: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    developer_instructions: Option<&'a str>,
+    history: HistoryToml,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    model_providers: BTreeMap<&'a str, ProviderToml<'a>>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    mcp_servers: BTreeMap<&'a str, McpServerToml<'a>>,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryToml {
+    persistence: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+struct ProviderToml<'a> {
+    name: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    base_url: Option<String>,
+    wire_api: &'static str,
+    // 已注入 bearer token 时不再输出 env_key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    experimental_bearer_token: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    env_key: Option<&'a str>,
+    requires_openai_auth: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    connect_timeout_ms: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    read_timeout_ms: Option<u32>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    http_headers: BTreeMap<&'a str, &'a str>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    query_params: BTreeMap<&'a str, &'a str>,
+}
+
+#[derive(Debug, Serialize)]
+struct McpServerToml<'a> {
+    #[serde(rename = "type")]
+    server_type: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<&'a str>,
+    #[serde(skip_serializing_if = "<[String]>::is_empty")]
+    args: &'a [String],
+    #[serde(skip_serializing_if = "Option::is_none")]
+    url: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    startup_timeout_sec: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tool_timeout_sec: Option<f64>,
+    // 子表须位于标量字段之后
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    env: BTreeMap<&'a str, &'a str>,
+}
+
+const DEFAULT_AUTO_COMPACT_TOKEN_LIMIT: u32 = 100_000;
+
+/// 序列化 SessionConfig；调用方负责事先校验
+pub fn render(config: &SessionConfig) -> Result<String, AdapterError> {
+    let persistence = match config.history_persistence() {
+        HistoryPersistence::SaveAll => "save-all",
+        HistoryPersistence::None => "none",
+    };
+    let doc = ConfigToml {
+        model_auto_compact_token_limit: match config.auto_compact_token_limit {
+            None => Some(DEFAULT_AUTO_COMPACT_TOKEN_LIMIT),
+            Some(0) => None,
+            limit => limit,
+        },
+        model: non_empty(&config.model),
+        model_provider: non_empty(&config.model_provider),
+        model_reasoning_effort: non_empty(&config.model_reasoning_effort),
+        model_verbosity: non_empty(&config.model_verbosity),
+        instructions: config.instructions.as_deref(),
+        developer_instructions: config.developer_instructions.as_deref(),
+        history: HistoryToml { persistence },
+        model_providers: config.provider_info.iter().map(|p| (p.name.as_str(), provider(p))).collect(),
+        mcp_servers: config.mcp_servers.iter().map(|(name, def)| (name.as_str(), mcp_server(def))).collect(),
+    };
+    toml::to_string(&doc).map_err(|e| anyhow::anyhow!("failed to serialize config.toml: {e}").into())
+}
+
+fn provider(p: &ModelProviderInfo) -> ProviderToml<'_> {
+    let wire_api = match WireApi::try_from(p.wire_api).unwrap_or(WireApi::Chat) {
+        WireApi::Chat => "chat",
+        WireApi::Responses => "responses",
+        WireApi::ResponsesWebsocket => "responses_websocket",
+        WireApi::Anthropic => "anthropic",
+    };
+    let bearer = p.experimental_bearer_token.as_deref();
+    ProviderToml {
+        name: &p.name,
+        base_url: p.base_url.as_deref().map(crate::normalize_base_url),
+        wire_api,
+        experimental_bearer_token: bearer,
+        env_key: if bearer.is_some() { None } else { p.env_key.as_deref() },
+        requires_openai_auth: p.requires_openai_auth,
+        connect_timeout_ms: p.connect_timeout_ms,
+        read_timeout_ms: p.read_timeout_ms,
+        http_headers: p.http_headers.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+        query_params: p.query_params.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+    }
+}
+
+fn mcp_server(def: &McpServerDef) -> McpServerToml<'_> {
+    McpServerToml {
+        server_type: &def.server_type,
+        command: non_empty(&def.command),
+        args: &def.args,
+        url: non_empty(&def.url),
+        startup_timeout_sec: def.startup_timeout_sec,
+        tool_timeout_sec: def.tool_timeout_sec,
+        env: def.env.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect(),
+    }
+}
+
+fn non_empty(value: &str) -> Option<&str> {
+    (!value.is_empty()).then_some(value)
+}
@@ -0,0 +1,79 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use tonic::Status;
+
+/// Adapter 内部错误，按类别映射到对应的 gRPC 状态码
+#[derive(Debug, thiserror::Error)]
+pub enum AdapterError {
+    #[error("no space left on device while writing {}", path.display())]
+    DiskFull { path: PathBuf },
+
+    #[error("{0}")]
+    InvalidArgument(String),
+
+    #[error("{0}")]
+    NotFound(String),
+
+    #[error("{0}")]
+    DataLoss(String),
+
+    #[error("{0}")]
+    ResourceExhausted(String),
+
+    #[error("{0}")]
+    DeadlineExceeded(String),
+
+    #[error("process timeout: {0}")]
+    ProcessTimeout(String),
+
+    #[error("codex binary not found at {} (set CODEX_BIN to the codex executable)", .0.display())]
+    BinaryMissing(PathBuf),
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Other(#[from] anyhow::Error),
+}
+
+impl From<AdapterError> for Status {
+    fn from(e: AdapterError) -> Self {
+        match e {
+            AdapterError::DiskFull { .. } => Status::resource_exhausted(e.to_string()),
+            AdapterError::InvalidArgument(msg) => Status::invalid_argument(msg),
+            AdapterError::NotFound(msg) => Status::not_found(msg),
+            AdapterError::DataLoss(msg) => Status::data_loss(msg),
+            AdapterError::ResourceExhausted(msg) => Status::resource_exhausted(msg),
+            AdapterError::DeadlineExceeded(msg) => Status::deadline_exceeded(msg),
+            AdapterError::ProcessTimeout(_) => Status::deadline_exceeded(e.to_string()),
+            AdapterError::BinaryMissing(_) => Status::failed_precondition(e.to_string()),
+            AdapterError::Io(_) | AdapterError::Other(_) => Status::internal(e.to_string()),
+        }
+    }
+}
+
+/// 将文件系统错误归类；磁盘写满 (ENOSPC) 单独识别，其余错误保留路径上下文
+pub fn map_io_error(e: io::Error, path: &Path) -> AdapterError {
+    if e.kind() == io::ErrorKind::StorageFull || is_enospc(&e) {
+        return AdapterError::DiskFull { path: path.to_path_buf() };
+    }
+    AdapterError::Other(anyhow::Error::new(e).context(format!("I/O error on {}", path.display())))
+}
+
+#[cfg(unix)]
+fn is_enospc(e: &io::Error) -> bool {
+    e.raw_os_error() == Some(libc::ENOSPC)
+}
+
+#[cfg(not(unix))]
+fn is_enospc(_e: &io::Error) -> bool {
+    false
+}
+
+pub async fn write_file(path: &Path, contents: impl AsRef<[u8]>) -> Result<(), AdapterError> {
+    tokio::fs::write(path, contents).await.map_err(|e| map_io_error(e, path))
+}
+
+pub async fn create_dir_all(path: &Path) -> Result<(), AdapterError> {
+    tokio::fs::create_dir_all(path).await.map_err(|e| map_io_error(e, path))
+}
@@ -0,0 +1,80 @@
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tracing::warn;
+
+use crate::agent::RunTaskResponse;
+use crate::agent::run_task_response::Event;
+use crate::{EventSender, event_response};
+
+/// 客户端跟不上时可丢弃的增量事件；错误、结束与 rollout (含 RolloutDelta) 等始终送达。
+/// 结构化 codex 事件与 codex_event_json 同等对待，但 codex 的 error 与 task_complete 始终送达
+pub fn is_droppable(event: &Event) -> bool {
+    matches!(
+        event,
+        Event::CodexEventJson(_)
+            | Event::AgentMessage(_)
+            | Event::AgentMessageDelta(_)
+            | Event::ExecCommandBegin(_)
+            | Event::ExecCommandEnd(_)
+            | Event::PatchApply(_)
+            | Event::AdapterLog(_)
+            | Event::AdapterCommentary(_)
+            | Event::Heartbeat(_)
+            | Event::TokenUsage(_)
+            | Event::WorkspaceFileEvent(_)
+            | Event::TaskDurationWarning(_)
+    )
+}
+
+/// drop_on_backpressure：任务写入中转通道，由中转任务转发给客户端。
+/// 客户端通道已满时丢弃可丢弃的事件，其余事件等待送达；丢弃后以一条 AdapterLog 报告丢弃数量
+pub fn spawn(client: EventSender, capacity: usize) -> EventSender {
+    let (tx, rx) = mpsc::channel(capacity);
+    tokio::spawn(relay(rx, client));
+    tx
+}
+
+async fn relay(mut rx: mpsc::Receiver<Result<RunTaskResponse, tonic::Status>>, client: EventSender) {
+    let mut dropped: u64 = 0;
+    loop {
+        let item = tokio::select! {
+            item = rx.recv() => item,
+            // 客户端断开后关闭中转通道，任务侧的 tx.closed() 随之触发
+            _ = client.closed() => return,
+        };
+        let Some(item) = item else { break };
+        let droppable = matches!(&item, Ok(RunTaskResponse { event: Some(event), .. }) if is_droppable(event));
+        if !droppable {
+            if dropped > 0 && client.send(Ok(dropped_notice(dropped))).await.is_ok() { dropped = 0; }
+            if client.send(item).await.is_err() { return; }
+            continue;
+        }
+        if dropped > 0 {
+            match client.try_send(Ok(dropped_notice(dropped))) {
+                Ok(()) => dropped = 0,
+                Err(TrySendError::Full(_)) => {
+                    dropped += 1;
+                    continue;
+                }
+                Err(TrySendError::Closed(_)) => return,
+            }
+        }
+        match client.try_send(item) {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                if dropped == 0 { warn!("Client is not keeping up, dropping low-priority events"); }
+                dropped += 1;
+            }
+            Err(TrySendError::Closed(_)) => return,
+        }
+    }
+    if dropped > 0 {
+        let _ = client.send(Ok(dropped_notice(dropped))).await;
+    }
+}
+
+fn dropped_notice(dropped: u64) -> RunTaskResponse {
+    event_response(Event::AdapterLog(format!(
+        "dropped {dropped} low-priority events (codex_event_json, adapter_log, heartbeat, ...) because the client was not keeping up (drop_on_backpressure)"
+    )))
+}
@@ -0,0 +1,266 @@
+use std::collections::HashSet;
+use std::sync::LazyLock;
+
+use regex::Regex;
+use serde_json::Value;
+
+use crate::agent::run_task_response::Event;
+use crate::agent::{AdapterCommentary, AgentMessage, AgentMessageDelta, CodexError, CodexEventKind, CodexTaskComplete, ErrorKind, ExecCommandBegin, ExecCommandEnd, PatchApply, TokenUsage};
+
+// codex exec --json 中代表工具调用的 item 类型
+const TOOL_ITEM_TYPES: &[&str] = &["command_execution", "mcp_tool_call", "web_search", "file_change"];
+
+/// 根据 codex 的 JSONL 事件流识别任务里程碑，生成可直接展示的进度说明
+#[derive(Debug, Default)]
+pub struct CommentaryTracker {
+    tool_calls: u32,
+    in_flight_tools: HashSet<String>,
+    tools_complete_reported: bool,
+    last_message: Option<String>,
+    tokens_used: u64,
+}
+
+impl CommentaryTracker {
+    pub fn observe(&mut self, event: &Value) -> Vec<AdapterCommentary> {
+        let mut out = Vec::new();
+        let item = event.get("item");
+        let item_type = item.and_then(|i| i.get("type")).and_then(Value::as_str).unwrap_or_default();
+        let item_id = item.and_then(|i| i.get("id")).and_then(Value::as_str).unwrap_or_default().to_string();
+
+        match event.get("type").and_then(Value::as_str).unwrap_or_default() {
+            "item.started" | "item.completed" if TOOL_ITEM_TYPES.contains(&item_type) => {
+                let started = event["type"] == "item.started";
+                if started || !self.in_flight_tools.contains(&item_id) {
+                    self.tool_calls += 1;
+                    if self.tool_calls == 1 {
+                        out.push(commentary("first_tool_call", item_type));
+                    }
+                }
+                if started {
+                    self.in_flight_tools.insert(item_id);
+                } else {
+                    self.in_flight_tools.remove(&item_id);
+                }
+            }
+            "item.completed" if item_type == "agent_message" => {
+                self.report_tools_complete(&mut out);
+                self.last_message = item.and_then(|i| i.get("text")).and_then(Value::as_str).map(str::to_string);
+            }
+            "turn.completed" => {
+                self.report_tools_complete(&mut out);
+                if let Some(text) = self.last_message.take() {
+                    out.push(commentary("final_message", &truncate(&text, 200)));
+                }
+                if let Some(usage) = event.get("usage") {
+                    let count = |key: &str| usage.get(key).and_then(Value::as_u64).unwrap_or_default();
+                    self.tokens_used += count("input_tokens") + count("output_tokens");
+                }
+                let detail = event.get("usage").map(Value::to_string).unwrap_or_default();
+                out.push(commentary("task_complete", &detail));
+            }
+            _ => {}
+        }
+        out
+    }
+
+    /// 截至目前所有已完成轮次的输入 + 输出 token 累计
+    pub fn tokens_used(&self) -> u64 {
+        self.tokens_used
+    }
+
+    fn report_tools_complete(&mut self, out: &mut Vec<AdapterCommentary>) {
+        if self.tool_calls > 0 && self.in_flight_tools.is_empty() && !self.tools_complete_reported {
+            self.tools_complete_reported = true;
+            out.push(commentary("tool_calls_complete", &format!("{} tool call(s)", self.tool_calls)));
+        }
+    }
+}
+
+/// 识别 codex 的用量事件：exec --json 的 turn.completed.usage，
+/// 以及协议事件 token_count (取 info.last_token_usage，即本轮用量)
+pub fn token_usage(event: &Value) -> Option<TokenUsage> {
+    let usage = match event.get("type").and_then(Value::as_str)? {
+        "turn.completed" => event.get("usage")?,
+        "token_count" => event.get("info")?.get("last_token_usage")?,
+        _ => return None,
+    };
+    let count = |key: &str| usage.get(key).and_then(Value::as_u64);
+    let input_tokens = count("input_tokens").unwrap_or_default();
+    let output_tokens = count("output_tokens").unwrap_or_default();
+    Some(TokenUsage {
+        input_tokens,
+        cached_input_tokens: count("cached_input_tokens").unwrap_or_default(),
+        output_tokens,
+        total_tokens: count("total_tokens").unwrap_or(input_tokens + output_tokens),
+    })
+}
+
+// codex 常见的鉴权与配额失败输出 (小写匹配)；状态码与关键词按词边界匹配，
+// 避免 "port 4010"、"authentication succeeded" 之类的行被误判
+static AUTH_ERROR_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b401\b|\bunauthorized\b|\b(invalid|incorrect)[ _]api[ _]key\b|\bauthentication (failed|error|required)\b|\bnot logged in\b")
+        .expect("valid auth error pattern")
+});
+static QUOTA_ERROR_PATTERN: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"\b429\b|\bquota\b|\brate[ _]limit(ed)?\b|\bbilling\b|\busage limit\b").expect("valid quota error pattern")
+});
+
+/// 根据 codex 退出前的 stderr / 错误事件归类启动失败；越靠后的行越接近失败原因
+pub fn classify_failure(lines: &[&str]) -> ErrorKind {
+    for line in lines.iter().rev() {
+        let lower = line.to_ascii_lowercase();
+        if QUOTA_ERROR_PATTERN.is_match(&lower) { return ErrorKind::QuotaExceeded; }
+        if AUTH_ERROR_PATTERN.is_match(&lower) { return ErrorKind::AuthError; }
+    }
+    ErrorKind::Unknown
+}
+
+/// codex exec --json 的 error / turn.failed 事件中的错误信息
+pub fn error_message(event: &Value) -> Option<String> {
+    match event.get("type").and_then(Value::as_str)? {
+        "error" => event.get("message").and_then(Value::as_str).map(str::to_string),
+        "turn.failed" => event.get("error")?.get("message").and_then(Value::as_str).map(str::to_string),
+        _ => None,
+    }
+}
+
+/// 收集一轮中 codex 输出的 agent_message，轮次结束时合并为最终回复
+#[derive(Debug, Default)]
+pub struct FinalMessageTracker {
+    parts: Vec<String>,
+}
+
+impl FinalMessageTracker {
+    /// turn.completed 时返回本轮的完整回复；仅调用工具而无回复的轮次返回 None
+    pub fn observe(&mut self, event: &Value) -> Option<String> {
+        match event.get("type").and_then(Value::as_str).unwrap_or_default() {
+            "turn.started" => self.parts.clear(),
+            "item.completed" => {
+                let item = event.get("item")?;
+                if item.get("type").and_then(Value::as_str) != Some("agent_message") { return None; }
+                let text = message_text(item);
+                if !text.is_empty() { self.parts.push(text); }
+            }
+            "turn.completed" if !self.parts.is_empty() => return Some(std::mem::take(&mut self.parts).join("\n\n")),
+            _ => {}
+        }
+        None
+    }
+}
+
+// agent_message 的文本：text 字段，或按顺序拼接 content 数组中各段的 text
+fn message_text(item: &Value) -> String {
+    if let Some(text) = item.get("text").and_then(Value::as_str) {
+        return text.to_string();
+    }
+    item.get("content")
+        .and_then(Value::as_array)
+        .map(|parts| parts.iter().filter_map(|p| p.get("text").and_then(Value::as_str)).collect())
+        .unwrap_or_default()
+}
+
+/// typed_events：将可识别的 codex 事件映射为结构化事件，其他事件返回 None (由调用方原样转发)。
+/// 同时识别协议事件 (可能以 {"id", "msg": {...}} 包裹) 与 exec --json 中对应的 item 事件
+pub fn typed_event(event: &Value) -> Option<Event> {
+    let msg = event.get("msg").filter(|m| m.get("type").is_some()).unwrap_or(event);
+    let text = |value: &Value, key: &str| value.get(key).and_then(Value::as_str).unwrap_or_default().to_string();
+    Some(match msg.get("type")?.as_str()? {
+        "agent_message" => Event::AgentMessage(AgentMessage { text: text(msg, "message") }),
+        "agent_message_delta" => Event::AgentMessageDelta(AgentMessageDelta { delta: text(msg, "delta") }),
+        "exec_command_begin" => Event::ExecCommandBegin(ExecCommandBegin {
+            call_id: text(msg, "call_id"),
+            command: command_parts(msg),
+            cwd: text(msg, "cwd"),
+        }),
+        "exec_command_end" => Event::ExecCommandEnd(ExecCommandEnd {
+            call_id: text(msg, "call_id"),
+            exit_code: exit_code(msg),
+            stdout: text(msg, "stdout"),
+            stderr: text(msg, "stderr"),
+        }),
+        "patch_apply_begin" => Event::PatchApply(PatchApply {
+            call_id: text(msg, "call_id"),
+            files: msg.get("changes").and_then(Value::as_object).map(|changes| changes.keys().cloned().collect()).unwrap_or_default(),
+            ..Default::default()
+        }),
+        "patch_apply_end" => Event::PatchApply(PatchApply {
+            call_id: text(msg, "call_id"),
+            completed: true,
+            success: msg.get("success").and_then(Value::as_bool).unwrap_or_default(),
+            stdout: text(msg, "stdout"),
+            stderr: text(msg, "stderr"),
+            ..Default::default()
+        }),
+        "task_complete" => Event::CodexTaskComplete(CodexTaskComplete { last_agent_message: text(msg, "last_agent_message") }),
+        "error" | "turn.failed" => Event::CodexError(CodexError { message: error_message(msg)? }),
+        kind @ ("item.started" | "item.completed") => return typed_item(msg.get("item")?, kind == "item.completed"),
+        _ => return None,
+    })
+}
+
+// exec --json 的 item 事件：agent_message、command_execution 与 file_change
+fn typed_item(item: &Value, completed: bool) -> Option<Event> {
+    let id = item.get("id").and_then(Value::as_str).unwrap_or_default().to_string();
+    Some(match (item.get("type")?.as_str()?, completed) {
+        ("agent_message", true) => Event::AgentMessage(AgentMessage { text: message_text(item) }),
+        ("command_execution", false) => Event::ExecCommandBegin(ExecCommandBegin { call_id: id, command: command_parts(item), cwd: String::new() }),
+        ("command_execution", true) => Event::ExecCommandEnd(ExecCommandEnd {
+            call_id: id,
+            exit_code: exit_code(item),
+            stdout: item.get("aggregated_output").and_then(Value::as_str).unwrap_or_default().to_string(),
+            stderr: String::new(),
+        }),
+        ("file_change", true) => Event::PatchApply(PatchApply {
+            call_id: id,
+            completed: true,
+            success: item.get("status").and_then(Value::as_str) == Some("completed"),
+            files: item
+                .get("changes")
+                .and_then(Value::as_array)
+                .map(|changes| changes.iter().filter_map(|c| c.get("path").and_then(Value::as_str)).map(str::to_string).collect())
+                .unwrap_or_default(),
+            ..Default::default()
+        }),
+        _ => return None,
+    })
+}
+
+// 协议事件的 command 为 argv 数组，exec --json 的为完整命令行字符串
+fn command_parts(value: &Value) -> Vec<String> {
+    match value.get("command") {
+        Some(Value::Array(parts)) => parts.iter().filter_map(Value::as_str).map(str::to_string).collect(),
+        Some(Value::String(command)) => vec![command.clone()],
+        _ => Vec::new(),
+    }
+}
+
+fn exit_code(value: &Value) -> Option<i32> {
+    value.get("exit_code").and_then(Value::as_i64).and_then(|code| i32::try_from(code).ok())
+}
+
+/// event_filter 非空时的转发判断；error 事件始终转发，无法识别的类型不转发
+pub fn passes_filter(event: &Value, filter: &[CodexEventKind]) -> bool {
+    let kind = match event.get("type").and_then(Value::as_str).unwrap_or_default() {
+        "error" => return true,
+        "thread.started" => CodexEventKind::ThreadStarted,
+        "turn.started" => CodexEventKind::TurnStarted,
+        "turn.completed" => CodexEventKind::TurnCompleted,
+        "turn.failed" => CodexEventKind::TurnFailed,
+        "item.started" => CodexEventKind::ItemStarted,
+        "item.updated" => CodexEventKind::ItemUpdated,
+        "item.completed" => CodexEventKind::ItemCompleted,
+        _ => return false,
+    };
+    filter.contains(&kind)
+}
+
+fn commentary(step: &str, detail: &str) -> AdapterCommentary {
+    AdapterCommentary { step: step.to_string(), detail: detail.to_string() }
+}
+
+pub fn truncate(text: &str, max_chars: usize) -> String {
+    match text.char_indices().nth(max_chars) {
+        Some((idx, _)) => format!("{}…", &text[..idx]),
+        None => text.to_string(),
+    }
+}
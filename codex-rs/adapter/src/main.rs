@@ -1,266 +1,3894 @@
-use tonic::{transport::Server, Request, Response, Status};
+use tonic::{transport::{Identity, Server, ServerTlsConfig}, Request, Response, Status};
 use tokio::process::Command;
+use tokio::sync::Semaphore;
 use tokio::io::{AsyncBufReadExt, BufReader, AsyncWriteExt};
 use tokio_stream::wrappers::ReceiverStream;
 use std::process::Stdio;
+use std::time::Duration;
 use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use sha2::{Digest, Sha256};
 use tempfile::TempDir;
-use tracing::{info, error};
-use chrono::Datelike;
+use tracing::{debug, info, warn, error, Instrument};
+
+mod approvals;
+mod auth;
+mod capture;
+mod config;
+mod config_cache;
+mod config_toml;
+mod error;
+mod event_relay;
+mod events;
+mod profile;
+mod providers;
+mod registry;
+mod rollout;
+mod rollout_stream;
+mod session_log;
+mod telemetry;
+mod watcher;
+mod workspace_gc;
+
+use std::sync::Arc;
+use tokio_util::sync::CancellationToken;
+use dashmap::DashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use config::ServerConfig;
+use error::{AdapterError, write_file, create_dir_all};
+use events::CommentaryTracker;
+use session_log::SessionLog;
+use rollout::RolloutRedactor;
+use rollout_stream::RolloutStreamer;
+use registry::TaskRegistry;
+use workspace_gc::WorkspaceLease;
 
 pub mod agent {
     tonic::include_proto!("codex.agent");
+
+    #[cfg(feature = "reflection")]
+    pub const FILE_DESCRIPTOR_SET: &[u8] = tonic::include_file_descriptor_set!("adapter_descriptor");
 }
 
 use agent::agent_service_server::{AgentService, AgentServiceServer};
-use agent::{RunTaskRequest, RunTaskResponse, run_task_response::Event, SessionConfig, WireApi, SandboxPolicy};
+use agent::{RunTaskRequest, RunTaskResponse, run_task_response::Event, SessionConfig, WireApi, SandboxPolicy, HistoryPersistence, File, ContextInjectionDiffStats, ModelProviderInfo, SessionStarted, TaskDurationWarning, AggregatedTaskResponse, TaskComplete, Heartbeat, RolloutMetadata, AgentError, ErrorKind, CodexEventKind, FinalMessage, DryRun, TaskStarted, Cancelled, AdapterConfigResponse, ServerInfoResponse, CompactRolloutRequest, CompactRolloutResponse, ListTaskRegistryResponse, TaskState, CancelTaskRequest, CancelTaskResponse, ListSessionsRequest, ListSessionsResponse, ActiveSessionInfo, ListProvidersRequest, ListProvidersResponse, ProviderSummary};
+
+// 事件协议版本：每次新增或修改 RunTaskResponse 事件类型时递增
+const ADAPTER_EVENT_SCHEMA_VERSION: &str = "1.24";
+
+const LOG_LEVEL: &str = "info";
+
+// 上一次注入的上下文文件清单 (相对路径 -> SHA-256)，用于增量注入
+const FILE_MANIFEST_NAME: &str = ".codex-file-manifest.json";
+
+// 持久化工作区的说明文件
+const WORKSPACE_README_NAME: &str = ".codex-readme.md";
+
+// codex 启动后必须在此时间内产生第一行输出，否则视为卡死
+const DEFAULT_FIRST_EVENT_TIMEOUT_SECS: u64 = 60;
+
+// 临时错误后 resume 已有 rollout 时发送的提示；原 prompt 已在 rollout 中，重复发送会产生重复的用户轮次
+const RETRY_CONTINUATION_PROMPT: &str = "The previous attempt was interrupted by a transient error. Continue the task from where you left off.";
+
+// 工作区初始化 (写目录/历史/配置/上下文文件) 的默认超时
+const DEFAULT_WORKSPACE_INIT_TIMEOUT_SECS: u64 = 30;
+
+// aggregate_response 模式未设置 stdout 上限时的默认上限
+const DEFAULT_AGGREGATE_STDOUT_LIMIT_BYTES: u64 = 64 * 1024 * 1024;
+
+// 上下文文件路径的默认最大层级数，避免过深的目录树与路径长度超限
+const MAX_PATH_COMPONENTS: usize = 16;
+
+// stdout 关闭后继续读取 stderr 的最长时间
+const STDERR_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+// 为启动失败归类保留的 stderr (及 codex error 事件) 行数
+const STDERR_TAIL_LINES: usize = 50;
+
+// 在此时间内以非零退出码结束视为启动失败：stderr 可归类 (鉴权/配额) 或 codex 尚未输出任何事件时发送 AgentError
+const STARTUP_FAILURE_WINDOW: Duration = Duration::from_secs(30);
+
+// 命名空间内的会话索引 (每行一个 {session_id, created_at})
+const NAMESPACE_INDEX_NAME: &str = "sessions-index.jsonl";
 
-#[derive(Debug, Default)]
-pub struct MyAgentService;
+// 相同 SessionConfig 复用已生成的 config.toml
+static CONFIG_CACHE: std::sync::LazyLock<config_cache::ConfigCache> =
+    std::sync::LazyLock::new(|| config_cache::ConfigCache::new(std::num::NonZeroUsize::new(256).expect("non-zero capacity")));
+
+static NEXT_ACTIVE_TASK_ID: AtomicU64 = AtomicU64::new(0);
+
+// 所有活跃会话已注入的上下文文件总字节数
+static TOTAL_WORKSPACE_BYTES: AtomicU64 = AtomicU64::new(0);
+
+type EventSender = tokio::sync::mpsc::Sender<Result<RunTaskResponse, Status>>;
+
+#[derive(Debug)]
+pub struct MyAgentService {
+    config: Arc<ServerConfig>,
+    registry: Arc<TaskRegistry>,
+    // 关闭宽限期结束后取消，终止仍在运行的 codex
+    shutdown: CancellationToken,
+    // 并发任务许可，每个 codex 子进程持有一个
+    task_slots: Arc<Semaphore>,
+    // 运行中任务的取消令牌，供 CancelTask 按 session_id 查找
+    active_sessions: Arc<DashMap<String, ActiveTask>>,
+    // ListProviders 的在线模型列表缓存
+    model_lists: Arc<providers::ModelListCache>,
+}
+
+impl MyAgentService {
+    pub fn new(config: ServerConfig) -> Self {
+        let registry = Arc::new(TaskRegistry::new(config.task_registry_history));
+        let task_slots = Arc::new(Semaphore::new(config.max_concurrent_tasks));
+        Self { config: Arc::new(config), registry, shutdown: CancellationToken::new(), task_slots, active_sessions: Arc::default(), model_lists: Arc::default() }
+    }
+}
 
 #[tonic::async_trait]
 impl AgentService for MyAgentService {
     type RunTaskStream = ReceiverStream<Result<RunTaskResponse, Status>>;
 
     async fn run_task(&self, request: Request<RunTaskRequest>) -> Result<Response<Self::RunTaskStream>, Status> {
-        let req = request.into_inner();
-        let (tx, rx) = tokio::sync::mpsc::channel(100);
+        let trace = TraceContext::from_metadata(request.metadata());
+        let client_addr = request.remote_addr().map(|a| a.to_string()).unwrap_or_default();
+        let mut req = request.into_inner();
+        validate_request(&req).inspect_err(|_| telemetry::record_request("invalid_argument"))?;
+        // 立即拒绝而非排队；许可随后台任务结束 (含 panic) 一起释放
+        let max_tasks = self.config.max_concurrent_tasks;
+        let permit = self.task_slots.clone().try_acquire_owned().map_err(|_| {
+            telemetry::record_request("resource_exhausted");
+            Status::resource_exhausted(format!("too many concurrent tasks (limit {max_tasks})"))
+        })?;
+        debug!(in_flight = max_tasks - self.task_slots.available_permits(), "Acquired task slot");
+        let slot = TaskSlot { permit: Some(permit), slots: self.task_slots.clone(), max_tasks };
+        // 未指定 session_id 时自动分配，并通过首个事件告知客户端
+        let generated = req.session_id.is_empty();
+        if generated {
+            req.session_id = uuid::Uuid::new_v4().to_string();
+            info!(session_id = %req.session_id, "Generated session id");
+        }
+        let model = req.session_config.as_ref().map(|c| c.model.clone()).unwrap_or_default();
+        let cancel = CancellationToken::new();
+        // 在启动 codex 之前登记；guard 随任务 future 一起 drop，即使任务从未被调度也会移除
+        let active = ActiveSession::register(&self.active_sessions, &req.session_id, req.force, ActiveTask {
+            task_id: NEXT_ACTIVE_TASK_ID.fetch_add(1, Ordering::Relaxed),
+            cancel: cancel.clone(),
+            started_at: now_timestamp(),
+            started: std::time::Instant::now(),
+            model: model.clone(),
+            session_namespace: req.session_namespace.clone(),
+        })
+        .inspect_err(|_| telemetry::record_request("already_exists"))?;
+        telemetry::record_request("accepted");
+        let trace = trace.with_request_fallback(&req);
+        let (trace_id, parent_span_id) = trace.ids();
+        let span = tracing::info_span!("run_task", session_id = %req.session_id, request_id = %req.request_id, trace_id, parent_span_id);
+        let channel_cap = self.config.event_channel_cap;
+        let (tx, rx) = tokio::sync::mpsc::channel(channel_cap);
+        let config = self.config.clone();
+        let mut task = self.registry.start(&req.session_id, client_addr, model);
+        let shutdown = self.shutdown.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = handle_run(req, tx.clone()).await {
-                error!("Task failed: {:?}", e);
-                let _ = tx.send(Ok(RunTaskResponse {
-                    event: Some(Event::Error(format!("Agent error: {}", e))),
-                })).await;
+            let _permit = slot;
+            let _in_flight = telemetry::InFlightTask::start();
+            let _active = active;
+            let started = std::time::Instant::now();
+            // 聚合模式下事件先汇入收集任务，任务结束后作为单条消息发出
+            let (task_tx, aggregator) = if req.aggregate_response {
+                let (task_tx, task_rx) = tokio::sync::mpsc::channel(channel_cap);
+                (task_tx, Some(tokio::spawn(collect_events(task_rx, tx.clone()))))
+            } else if req.drop_on_backpressure {
+                (event_relay::spawn(tx.clone(), channel_cap), None)
+            } else {
+                (tx.clone(), None)
+            };
+
+            let exit_code = match handle_run(req, generated, config, trace, task_tx.clone(), shutdown, cancel).await {
+                Ok(code) => {
+                    if code == Some(0) { task.set_final_state(TaskState::Completed); }
+                    code
+                }
+                Err(e) => {
+                    error!("Task failed: {:?}", e);
+                    let item = match e {
+                        AdapterError::Io(_) | AdapterError::Other(_) => Ok(event_response(Event::Error(format!("Agent error: {}", e)))),
+                        AdapterError::ProcessTimeout(_) => Ok(event_response(Event::Error(e.to_string()))),
+                        AdapterError::BinaryMissing(_) => Ok(event_response(Event::AgentError(AgentError { kind: ErrorKind::BinaryMissing as i32, message: e.to_string() }))),
+                        e => Err(Status::from(e)),
+                    };
+                    let _ = task_tx.send(item).await;
+                    None
+                }
+            };
+            drop(task_tx);
+
+            if let Some(aggregator) = aggregator {
+                let item = match aggregator.await {
+                    Ok(Ok(events)) => Ok(event_response(Event::AggregatedTaskResponse(AggregatedTaskResponse {
+                        events,
+                        total_duration_ms: started.elapsed().as_millis() as u64,
+                        final_exit_code: exit_code.unwrap_or(-1),
+                    }))),
+                    Ok(Err(status)) => Err(status),
+                    Err(e) => Err(Status::internal(format!("event aggregation failed: {e}"))),
+                };
+                let _ = tx.send(item).await;
             }
-        });
+        }.instrument(span));
 
         Ok(Response::new(ReceiverStream::new(rx)))
     }
+
+    async fn get_server_info(&self, _request: Request<()>) -> Result<Response<ServerInfoResponse>, Status> {
+        let (codex_binary_path, codex_binary_sha256) = codex_binary_fingerprint(&self.config.codex_bin).await.clone();
+        Ok(Response::new(ServerInfoResponse {
+            adapter_version: env!("CARGO_PKG_VERSION").to_string(),
+            event_schema_version: ADAPTER_EVENT_SCHEMA_VERSION.to_string(),
+            codex_binary_path,
+            codex_binary_sha256,
+        }))
+    }
+
+    async fn get_adapter_config(&self, request: Request<()>) -> Result<Response<AdapterConfigResponse>, Status> {
+        auth::check_admin(request.metadata(), self.config.admin_token.as_deref())?;
+        let config = &self.config;
+        let path_string = |p: &Option<PathBuf>| p.as_ref().map(|p| p.display().to_string()).unwrap_or_default();
+        Ok(Response::new(AdapterConfigResponse {
+            validate_rollout_on_resume: config.validate_rollout_on_resume,
+            max_total_workspace_bytes: config.max_total_workspace_bytes.unwrap_or(0),
+            max_request_bytes: config.max_request_bytes as u64,
+            profiles_dir: path_string(&config.profiles_dir),
+            gc_interval_secs: config.workspace_gc_interval_secs,
+            shared_workspace_root: path_string(&config.shared_workspace_root),
+            persistent_workspace_root: path_string(&config.persistent_workspace_root),
+            log_level: LOG_LEVEL.to_string(),
+            admin_token_configured: config.admin_token.is_some(),
+            max_subprocess_stdout_bytes: config.max_subprocess_stdout_bytes.unwrap_or(0),
+            auto_inject_openai_api_key: config.auto_inject_openai_api_key,
+            auto_inject_anthropic_api_key: config.auto_inject_anthropic_api_key,
+            default_task_timeout_secs: config.default_task_timeout_secs.unwrap_or(0),
+            server_token_configured: config.server_token.is_some(),
+            heartbeat_interval_secs: config.heartbeat_interval_secs,
+            max_concurrent_tasks: config.max_concurrent_tasks as u32,
+            tls_enabled: config.tls_cert_path.is_some() && config.tls_key_path.is_some(),
+            env_passthrough: config.env_passthrough.clone(),
+            max_context_bytes: config.max_context_bytes,
+            max_context_file_bytes: config.max_context_file_bytes,
+            scratch_root: config.scratch_root.display().to_string(),
+            keep_scratch: config.keep_scratch,
+            metrics_addr: config.metrics_addr.clone(),
+            transient_error_pattern: config.transient_error_pattern.clone(),
+            retry_backoff_base_ms: config.retry_backoff_base_ms,
+            max_stderr_lines: config.max_stderr_lines,
+            max_stderr_bytes: config.max_stderr_bytes,
+            event_channel_cap: config.event_channel_cap as u32,
+            cancel_grace_secs: config.cancel_grace_secs,
+            log_dir: path_string(&config.log_dir),
+        }))
+    }
+
+    async fn list_task_registry(&self, request: Request<()>) -> Result<Response<ListTaskRegistryResponse>, Status> {
+        auth::check_admin(request.metadata(), self.config.admin_token.as_deref())?;
+        Ok(Response::new(ListTaskRegistryResponse { tasks: self.registry.snapshot() }))
+    }
+
+    // 与 RunTask 一样只经过 bearer 鉴权；只能取消已知 session_id 的任务 (通常是调用方自己的会话)
+    async fn cancel_task(&self, request: Request<CancelTaskRequest>) -> Result<Response<CancelTaskResponse>, Status> {
+        let session_id = request.into_inner().session_id;
+        // 重复取消同一会话只会再次触发已取消的令牌；任务结束后即从 active_sessions 移除
+        let Some(active) = self.active_sessions.get(&session_id) else {
+            return Err(Status::not_found(format!("no running task for session {session_id:?}")));
+        };
+        info!(session_id, "Cancelling task on request");
+        active.cancel.cancel();
+        Ok(Response::new(CancelTaskResponse { found: true }))
+    }
+
+    async fn list_sessions(&self, request: Request<ListSessionsRequest>) -> Result<Response<ListSessionsResponse>, Status> {
+        auth::check_admin(request.metadata(), self.config.admin_token.as_deref())?;
+        let filter = request.into_inner().filter_namespace;
+        let mut sessions: Vec<ActiveSessionInfo> = self.active_sessions.iter()
+            .filter(|entry| filter.is_empty() || entry.session_namespace == filter)
+            .map(|entry| ActiveSessionInfo {
+                session_id: entry.key().clone(),
+                started_at: Some(entry.started_at.clone()),
+                model: entry.model.clone(),
+                elapsed_secs: entry.started.elapsed().as_secs(),
+                session_namespace: entry.session_namespace.clone(),
+            })
+            .collect();
+        // 运行最久的在前
+        sessions.sort_by(|a, b| b.elapsed_secs.cmp(&a.elapsed_secs));
+        Ok(Response::new(ListSessionsResponse { sessions }))
+    }
+
+    async fn list_providers(&self, request: Request<ListProvidersRequest>) -> Result<Response<ListProvidersResponse>, Status> {
+        let refresh = request.into_inner().refresh_model_cache;
+        let Some(dir) = self.config.profiles_dir.as_deref() else { return Ok(Response::new(ListProvidersResponse::default())) };
+        let profiles = profile::provider_profiles(dir).await?;
+        // 各提供方的模型列表并发获取，单个提供方最多等待 5s
+        let live = futures::future::join_all(profiles.iter().map(|p| self.model_lists.models(&p.provider, refresh))).await;
+        let providers = profiles.into_iter().zip(live).map(|(entry, live)| {
+            let mut model_suggestions: Vec<String> = entry.model.into_iter().collect();
+            for model in live {
+                if !model_suggestions.contains(&model) { model_suggestions.push(model); }
+            }
+            ProviderSummary {
+                profile: entry.profile,
+                name: entry.provider.name,
+                base_url: entry.provider.base_url.unwrap_or_default(),
+                wire_api: entry.provider.wire_api,
+                model_list_endpoint: entry.provider.model_list_endpoint,
+                model_suggestions,
+            }
+        }).collect();
+        Ok(Response::new(ListProvidersResponse { providers }))
+    }
+
+    async fn compact_rollout(&self, request: Request<CompactRolloutRequest>) -> Result<Response<CompactRolloutResponse>, Status> {
+        auth::check_admin(request.metadata(), self.config.admin_token.as_deref())?;
+        let req = request.into_inner();
+        Ok(Response::new(compact_session_rollout(&self.config, req).await?))
+    }
 }
 
-async fn handle_run(mut req: RunTaskRequest, tx: tokio::sync::mpsc::Sender<Result<RunTaskResponse, Status>>) -> anyhow::Result<()> {
-    // 1. 准备隔离的工作环境
-    let temp_dir = TempDir::new()?;
-    let codex_home = temp_dir.path();
-    let work_dir = if !req.base_dir.is_empty() {
-        Path::new(&req.base_dir).to_path_buf()
-    } else {
-        codex_home.join("workspace")
-    };
-    tokio::fs::create_dir_all(&work_dir).await?;
+// 持有并发许可；drop 时释放并记录剩余的在途任务数
+struct TaskSlot {
+    permit: Option<tokio::sync::OwnedSemaphorePermit>,
+    slots: Arc<Semaphore>,
+    max_tasks: usize,
+}
 
-    // 2. 灵魂复活逻辑 (State Revival)
-    let is_resuming = !req.history_rollout.is_empty();
-    if is_resuming {
-        let now = chrono::Utc::now();
-        let session_path = codex_home.join(format!("sessions/{}/{:02}/{:02}", now.year(), now.month(), now.day()));
-        tokio::fs::create_dir_all(&session_path).await?;
-        let history_file = session_path.join(format!("rollout-{}.jsonl", req.session_id));
-        tokio::fs::write(&history_file, &req.history_rollout).await?;
-        info!(session_id = %req.session_id, "Revived session state");
+impl Drop for TaskSlot {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        debug!(in_flight = self.max_tasks - self.slots.available_permits(), "Released task slot");
     }
+}
 
-    // 3. 动态配置注入
-    if let Some(config) = &mut req.session_config {
-        if let Some(prov) = &mut config.provider_info {
-            if let Some(key) = &prov.env_key {
-                if let Some(val) = req.env_vars.get(key) {
-                    prov.experimental_bearer_token = Some(val.clone());
-                }
+// 运行中任务的取消令牌与 ListSessions 展示的信息
+#[derive(Debug)]
+struct ActiveTask {
+    // 区分 force 覆盖前后同名会话的登记，避免先结束的任务移除后来者
+    task_id: u64,
+    cancel: CancellationToken,
+    started_at: prost_types::Timestamp,
+    started: std::time::Instant,
+    model: String,
+    // ListSessions 的 filter_namespace 按此过滤
+    session_namespace: String,
+}
+
+// 任务结束 (含 panic) 时从 active_sessions 中移除
+struct ActiveSession {
+    sessions: Arc<DashMap<String, ActiveTask>>,
+    session_id: String,
+    task_id: u64,
+}
+
+impl ActiveSession {
+    // 同名会话仍在运行时返回 ALREADY_EXISTS；force 时覆盖登记 (旧任务不再能被 CancelTask 找到)
+    fn register(sessions: &Arc<DashMap<String, ActiveTask>>, session_id: &str, force: bool, task: ActiveTask) -> Result<Self, Status> {
+        let task_id = task.task_id;
+        match sessions.entry(session_id.to_string()) {
+            dashmap::mapref::entry::Entry::Occupied(_) if !force => {
+                return Err(Status::already_exists(format!("session {session_id:?} is already running (set force to run it anyway)")));
+            }
+            dashmap::mapref::entry::Entry::Occupied(mut entry) => {
+                warn!(session_id, "force is set: starting a second run of an already running session");
+                entry.insert(task);
+            }
+            dashmap::mapref::entry::Entry::Vacant(entry) => {
+                entry.insert(task);
             }
         }
-        tokio::fs::write(codex_home.join("config.toml"), generate_config_toml(config)?).await?;
+        Ok(Self { sessions: sessions.clone(), session_id: session_id.to_string(), task_id })
     }
+}
 
-    // 4. 注入上下文文件
-    for file in &req.context_files {
-        if file.path.contains("..") || file.path.starts_with("/") { continue; }
-        let path = work_dir.join(&file.path);
-        if let Some(parent) = path.parent() { tokio::fs::create_dir_all(parent).await?; }
-        tokio::fs::write(&path, &file.content).await?;
+impl Drop for ActiveSession {
+    fn drop(&mut self) {
+        self.sessions.remove_if(&self.session_id, |_, task| task.task_id == self.task_id);
     }
+}
 
-    // 5. 构建并启动 Codex 子进程
-    let mut cmd = build_codex_command(&req, codex_home, &work_dir);
-    let mut child = cmd.spawn()?;
+// 收集任务产生的全部事件；遇到 gRPC 错误时停止收集并返回该错误。
+// 客户端断开时放弃收集并关闭 rx，使任务侧同样感知到断开
+async fn collect_events(mut rx: tokio::sync::mpsc::Receiver<Result<RunTaskResponse, Status>>, client: EventSender) -> Result<Vec<RunTaskResponse>, Status> {
+    let mut events = Vec::new();
+    loop {
+        tokio::select! {
+            item = rx.recv() => match item {
+                Some(item) => events.push(item?),
+                None => return Ok(events),
+            },
+            _ = client.closed() => return Err(Status::cancelled("client disconnected")),
+        }
+    }
+}
 
-    // 注入 Prompt
-    if let Some(mut stdin) = child.stdin.take() {
-        let full_prompt = build_full_prompt(&req.prompt, req.session_config.as_ref());
-        stdin.write_all(full_prompt.as_bytes()).await?;
-        drop(stdin);
+// 定位 CODEX_HOME_TEMPLATE 下保存的会话 rollout，压缩后原子写回
+async fn compact_session_rollout(config: &ServerConfig, req: CompactRolloutRequest) -> Result<CompactRolloutResponse, AdapterError> {
+    if req.session_id.is_empty() {
+        return Err(AdapterError::InvalidArgument("session_id is required".to_string()));
     }
+    let Some(template) = &config.codex_home_template else {
+        return Err(AdapterError::NotFound(format!("rollout for session {:?} not found: CODEX_HOME_TEMPLATE is not set", req.session_id)));
+    };
+    let lookup = RunTaskRequest { session_id: req.session_id.clone(), tenant_id: req.tenant_id.clone(), ..Default::default() };
+    validate_session_namespace(&req.session_namespace)?;
+    let mut codex_home = render_codex_home(template, config.codex_home_root.as_deref(), &lookup)?;
+    if !req.session_namespace.is_empty() {
+        codex_home = namespace_home(&codex_home, &req.session_namespace);
+    }
+    // walkdir 为阻塞遍历，避免占用 async 运行时线程
+    let (sessions_dir, id) = (codex_home.join("sessions"), req.session_id.clone());
+    let found = tokio::task::spawn_blocking(move || find_rollout_file(&sessions_dir, &id)).await.map_err(anyhow::Error::from)??;
+    let Some(path) = found else {
+        // 不在 Status 中暴露服务端路径
+        warn!(session_id = %req.session_id, codex_home = %codex_home.display(), "Rollout to compact not found");
+        return Err(AdapterError::NotFound(format!("rollout for session {:?} not found", req.session_id)));
+    };
 
-    // 6. 实时流处理与灵魂提取
-    process_streams(child, tx, codex_home, &req.session_id).await?;
+    let original = tokio::fs::read(&path).await?;
+    let (compacted, turns_removed) = rollout::compact(&original, req.keep_last_n_turns as usize);
+    if turns_removed > 0 {
+        let tmp_path = path.with_extension("jsonl.tmp");
+        write_file(&tmp_path, &compacted).await?;
+        tokio::fs::rename(&tmp_path, &path).await?;
+    }
+    info!(session_id = %req.session_id, path = %path.display(), turns_removed, "Compacted rollout");
+    Ok(CompactRolloutResponse {
+        original_bytes: original.len() as u64,
+        compacted_bytes: compacted.len() as u64,
+        turns_removed: turns_removed as u32,
+    })
+}
 
-    Ok(())
+// rollout 文件名为 `rollout-{时间戳}-{id}.jsonl`，须以 `-{id}.jsonl` 结尾，避免 id 为另一会话 id 的后缀时误匹配
+fn is_rollout_file_for(name: &str, id: &str) -> bool {
+    name.starts_with("rollout-") && name.strip_suffix(".jsonl").and_then(|stem| stem.strip_suffix(id)).is_some_and(|rest| rest.ends_with('-'))
 }
 
-fn build_codex_command(req: &RunTaskRequest, codex_home: &Path, work_dir: &Path) -> Command {
-    let mut cmd = Command::new("codex");
-    
-    // 配置全局覆盖参数 (必须在子命令前)
-    if let Some(config) = &req.session_config {
-        if !config.model.is_empty() {
-            cmd.arg("-c").arg(format!("model={}", config.model));
+// 在 sessions 目录下递归查找属于 id 的 rollout
+fn find_rollout_file(dir: &Path, id: &str) -> Result<Option<PathBuf>, AdapterError> {
+    if !dir.is_dir() { return Ok(None); }
+    for entry in walkdir::WalkDir::new(dir).follow_links(false) {
+        let entry = entry.map_err(anyhow::Error::from)?;
+        if entry.file_type().is_file() && is_rollout_file_for(&entry.file_name().to_string_lossy(), id) {
+            return Ok(Some(entry.into_path()));
         }
-        if !config.model_provider.is_empty() {
-            cmd.arg("-c").arg(format!("model_provider={}", config.model_provider));
+    }
+    Ok(None)
+}
+
+// 从 gRPC metadata 中提取的 W3C Trace Context (traceparent / tracestate)
+#[derive(Debug, Default, Clone)]
+struct TraceContext {
+    traceparent: Option<String>,
+    tracestate: Option<String>,
+}
+
+impl TraceContext {
+    fn from_metadata(metadata: &tonic::metadata::MetadataMap) -> Self {
+        let get = |key: &str| metadata.get(key).and_then(|v| v.to_str().ok()).map(str::to_string);
+        Self { traceparent: get("traceparent"), tracestate: get("tracestate") }
+    }
+
+    // 无法设置 gRPC metadata 的客户端可通过 trace_id / parent_span_id 字段传入父 span；
+    // metadata 中的 traceparent 优先
+    fn with_request_fallback(mut self, req: &RunTaskRequest) -> Self {
+        if self.traceparent.is_none() && !req.trace_id.is_empty() {
+            self.traceparent = Some(format!("00-{}-{}-01", req.trace_id, req.parent_span_id));
         }
+        self
     }
 
-    cmd.arg("exec").arg("--json").arg("--skip-git-repo-check").arg("--dangerously-bypass-approvals-and-sandbox");
+    // (trace_id, parent span_id)，traceparent 缺失或格式不符时为空字符串
+    fn ids(&self) -> (&str, &str) {
+        let mut parts = self.traceparent.as_deref().unwrap_or_default().split('-');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(_version), Some(trace_id), Some(span_id)) => (trace_id, span_id),
+            _ => ("", ""),
+        }
+    }
+}
 
-    if let Some(config) = &req.session_config {
-        match SandboxPolicy::try_from(config.sandbox_policy).unwrap_or(SandboxPolicy::Unspecified) {
-            SandboxPolicy::WorkspaceWrite => { cmd.arg("--sandbox").arg("workspace-write"); },
-            SandboxPolicy::ReadOnly => { cmd.arg("--sandbox").arg("read-only"); },
-            SandboxPolicy::DangerFullAccess => { cmd.arg("--sandbox").arg("danger-full-access"); },
-            _ => {}
+// 由 Adapter 根据 SessionConfig 生成的配置 (键本身及其下的子键)，不允许经 config_overrides 绕过校验直接改写
+const MANAGED_CONFIG_KEYS: &[&str] = &["approval_policy", "sandbox_mode", "sandbox_workspace_write", "mcp_servers", "model_providers", "history"];
+
+fn is_managed_config_key(key: &str) -> bool {
+    key.starts_with("model_")
+        || MANAGED_CONFIG_KEYS.iter().any(|managed| key.strip_prefix(managed).is_some_and(|rest| rest.is_empty() || rest.starts_with('.')))
+}
+
+fn validate_config_overrides(overrides: &std::collections::HashMap<String, String>) -> Result<(), AdapterError> {
+    for (key, value) in overrides {
+        let key_ok = !key.is_empty() && !key.starts_with('.') && !key.ends_with('.') && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.');
+        if !key_ok {
+            return Err(AdapterError::InvalidArgument(format!("config_overrides key {key:?} must match [A-Za-z0-9_.]+")));
+        }
+        if is_managed_config_key(key) {
+            return Err(AdapterError::InvalidArgument(format!("config_overrides key {key:?} is managed by the adapter; set it through session_config instead")));
+        }
+        if value.chars().any(char::is_control) {
+            return Err(AdapterError::InvalidArgument(format!("config_overrides value for {key:?} must not contain control characters")));
+        }
+    }
+    Ok(())
+}
+
+fn is_hex_id(value: &str, len: usize) -> bool {
+    value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit() && !c.is_ascii_uppercase()) && value.chars().any(|c| c != '0')
+}
+
+// 在创建任何工作目录之前完成的请求级校验
+fn validate_request(req: &RunTaskRequest) -> Result<(), AdapterError> {
+    if !req.output_capture_mode.is_empty() && !capture::CAPTURE_MODES.contains(&req.output_capture_mode.as_str()) {
+        return Err(AdapterError::InvalidArgument(format!(
+            "unknown output_capture_mode {:?} (expected one of {:?})",
+            req.output_capture_mode,
+            capture::CAPTURE_MODES
+        )));
+    }
+    // 与 MCP server env 相同的校验：拒绝非法变量名与 LD_PRELOAD/BASH_ENV 等可注入代码的变量
+    for key in req.env_vars.keys() {
+        validate_env_var_name(key).map_err(|reason| AdapterError::InvalidArgument(format!("env var {key:?} {reason}")))?;
+    }
+    let violations = env_var_schema_violations(&req.env_var_schema, &req.env_vars);
+    if !violations.is_empty() {
+        return Err(AdapterError::InvalidArgument(format!("env_vars do not match env_var_schema: {}", violations.join("; "))));
+    }
+    if req.stdin_null_mode && !req.prompt.is_empty() {
+        return Err(AdapterError::InvalidArgument("prompt must be empty when stdin_null_mode is set".to_string()));
+    }
+    // 恢复会话可以只续跑而不追加输入；stdin_null_mode 与 dry_run_toml 不经 stdin 传入 prompt
+    let resuming = !req.history_rollout.is_empty();
+    if req.prompt.trim().is_empty() && !resuming && !req.stdin_null_mode && !req.dry_run_toml {
+        return Err(AdapterError::InvalidArgument("prompt is required for a new session".to_string()));
+    }
+    if !req.base_dir.is_empty() {
+        let base_dir = Path::new(&req.base_dir);
+        if !base_dir.is_absolute() {
+            return Err(AdapterError::InvalidArgument(format!("base_dir {:?} must be an absolute path", req.base_dir)));
+        }
+        if !base_dir.is_dir() {
+            return Err(AdapterError::InvalidArgument(format!("base_dir {:?} is not an existing directory", req.base_dir)));
+        }
+    }
+    validate_session_namespace(&req.session_namespace)?;
+    validate_config_overrides(&req.config_overrides)?;
+    if let Some(config) = &req.session_config { validate_session_config(config)?; }
+    // 两者都不设置，或同时设置且格式正确
+    let trace_unset = req.trace_id.is_empty() && req.parent_span_id.is_empty();
+    let trace_valid = is_hex_id(&req.trace_id, 32) && is_hex_id(&req.parent_span_id, 16);
+    if !trace_unset && !trace_valid {
+        return Err(AdapterError::InvalidArgument(
+            "trace_id must be 32 and parent_span_id 16 lowercase hex characters, and both must be set together".to_string(),
+        ));
+    }
+    let max_depth = match req.max_context_file_path_depth {
+        0 => MAX_PATH_COMPONENTS,
+        depth => depth as usize,
+    };
+    for file in &req.context_files {
+        let depth = Path::new(&normalize_context_file_path(&file.path)).components().count();
+        if depth > max_depth {
+            return Err(AdapterError::InvalidArgument(format!(
+                "context file {:?} has {depth} path components (limit {max_depth})",
+                file.path
+            )));
+        }
+        if let Some(mode) = file.mode
+            && mode > 0o777
+        {
+            return Err(AdapterError::InvalidArgument(format!("context file {:?} has invalid mode {mode:#o} (expected permission bits such as 0644 or 0755)", file.path)));
         }
     }
+    Ok(())
+}
 
-    if !req.history_rollout.is_empty() {
-        cmd.arg("resume").arg(&req.session_id);
+fn validate_session_namespace(ns: &str) -> Result<(), AdapterError> {
+    if ns.len() > 64 || !ns.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+        return Err(AdapterError::InvalidArgument(format!(
+            "invalid session_namespace {ns:?}: only letters, digits and '-' are allowed, at most 64 characters"
+        )));
     }
+    Ok(())
+}
 
-    cmd.arg("-")
-       .current_dir(work_dir)
-       .env("CODEX_HOME", codex_home)
-       .env("RUST_LOG", "info")
-       .envs(&req.env_vars)
-       .stdin(Stdio::piped())
-       .stdout(Stdio::piped())
-       .stderr(Stdio::piped());
-    
-    cmd
+// 按 env_var_schema 的类型提示校验 env_vars，返回全部违规项
+fn env_var_schema_violations(schema: &std::collections::HashMap<String, String>, env: &std::collections::HashMap<String, String>) -> Vec<String> {
+    let schema: BTreeMap<_, _> = schema.iter().collect();
+    let mut violations = Vec::new();
+    for (key, hint) in schema {
+        let value = env.get(key);
+        let ok = match (hint.as_str(), value) {
+            ("required", value) => value.is_some(),
+            ("optional", _) | ("url" | "integer" | "bool", None) => true,
+            ("url", Some(v)) => url::Url::parse(v).is_ok(),
+            ("integer", Some(v)) => v.parse::<i64>().is_ok(),
+            ("bool", Some(v)) => v == "true" || v == "false",
+            (other, _) => {
+                violations.push(format!("{key}: unknown schema type {other:?}"));
+                continue;
+            }
+        };
+        if !ok {
+            match value {
+                None => violations.push(format!("{key}: required but missing")),
+                Some(v) => violations.push(format!("{key}: {v:?} is not a valid {hint}")),
+            }
+        }
+    }
+    violations
 }
 
-async fn process_streams(mut child: tokio::process::Child, tx: tokio::sync::mpsc::Sender<Result<RunTaskResponse, Status>>, codex_home: &Path, session_id: &str) -> anyhow::Result<()> {
-    let stdout = child.stdout.take().unwrap();
-    let stderr = child.stderr.take().unwrap();
-    
-    let mut out_reader = BufReader::new(stdout).lines();
-    let mut err_reader = BufReader::new(stderr).lines();
+// 请求内联的 SessionConfig 与合并 config_profile 之后的结果都须通过的校验
+fn validate_session_config(config: &SessionConfig) -> Result<(), AdapterError> {
+    validate_model_params(config)?;
+    if let Some(root) = config.writable_roots.iter().find(|r| !Path::new(r).is_absolute()) {
+        return Err(AdapterError::InvalidArgument(format!("writable_roots entry {root:?} must be an absolute path")));
+    }
+    // codex exec 无头运行，固定以 never 处理审批，无法把审批请求交给客户端
+    if approvals::is_interactive(config.approval_policy()) {
+        return Err(AdapterError::InvalidArgument(format!(
+            "approval_policy {} is deprecated: it requires interactive approvals, which codex exec cannot relay; use NEVER or leave it unset",
+            config.approval_policy().as_str_name()
+        )));
+    }
+    Ok(())
+}
 
-    // 异步转发 STDERR 日志
-    let tx_err = tx.clone();
-    tokio::spawn(async move {
-        while let Ok(Some(line)) = err_reader.next_line().await {
-             let _ = tx_err.send(Ok(RunTaskResponse {
-                event: Some(agent::run_task_response::Event::AdapterLog(format!("[STDERR] {}", line)))
-            })).await;
+// 返回 codex 的退出码；codex 未运行或未正常退出时为 None
+async fn handle_run(mut req: RunTaskRequest, generated: bool, config: Arc<ServerConfig>, trace: TraceContext, tx: EventSender, shutdown: CancellationToken, cancel: CancellationToken) -> Result<Option<i32>, AdapterError> {
+    // TaskComplete.task_started_at：重试共用同一个开始时刻
+    let task_started_at = now_timestamp();
+    let workspace_ttl = (!req.base_dir.is_empty() && req.workspace_persistence_ttl_secs > 0).then(|| Duration::from_secs(u64::from(req.workspace_persistence_ttl_secs)));
+    let completion = TaskCompletion { task_started_at: &task_started_at, workspace_ttl };
+    let _ = tx.send(Ok(event_response(Event::SessionStarted(SessionStarted { session_id: req.session_id.clone(), generated })))).await;
+
+    let redactor = RolloutRedactor::compile(&req.rollout_line_filters)?;
+
+    // 展开命名配置预设，内联 session_config 中已设置的字段优先
+    if !req.config_profile.is_empty() {
+        let merged = profile::apply_profile(config.profiles_dir.as_deref(), &req.config_profile, req.session_config.take()).await?;
+        // dry_run_toml 下校验错误随预览一并返回
+        if !req.dry_run_toml {
+            validate_mcp_servers(&merged)?;
+            validate_session_config(&merged)?;
         }
-    });
+        req.session_config = Some(merged);
+    }
 
-    // 主循环：转发 STDOUT 中的 JSON 事件
-    while let Ok(Some(line)) = out_reader.next_line().await {
-        if tx.send(Ok(RunTaskResponse {
-            event: Some(agent::run_task_response::Event::CodexEventJson(line))
-        })).await.is_err() {
-            let _ = child.kill().await;
-            return Ok(());
+    if req.dry_run_toml {
+        let preview = preview_config_toml(req.session_config.as_ref());
+        let _ = tx.send(Ok(event_response(Event::AdapterLog(preview)))).await;
+        return Ok(None);
+    }
+
+    // dry_run 同样经过启动前的检查
+    inject_server_api_keys(&mut req.env_vars, &config);
+
+    check_context_file_sizes(&req.context_files, config.max_context_file_bytes, config.max_context_bytes)?;
+
+    if req.dry_run {
+        let dry_run = build_dry_run(&req, &config, &trace)?;
+        info!(session_id = %req.session_id, argv = ?dry_run.argv, "Dry run: codex not started");
+        let _ = tx.send(Ok(event_response(Event::DryRun(dry_run)))).await;
+        return Ok(None);
+    }
+
+    // 0. 全局工作区配额：在写入任何文件之前为本次请求预留空间
+    let incoming_bytes = req.context_files.iter().map(|f| f.content.len() as u64).sum();
+    let _workspace_bytes = WorkspaceBytesGuard::reserve(incoming_bytes, config.max_total_workspace_bytes)?;
+
+    // 1. 准备隔离的工作环境
+    // 配置了 CODEX_HOME_TEMPLATE 时使用按会话/租户渲染的固定目录，否则使用任务结束即删除的临时目录
+    let (temp_dir, scratch) = match &config.codex_home_template {
+        Some(_) => (None, PathBuf::new()),
+        None => {
+            create_dir_all(&config.scratch_root).await?;
+            let temp_dir = TempDir::new_in(&config.scratch_root)?;
+            if req.keep_scratch || config.keep_scratch {
+                // 立即放弃 TempDir 的所有权，任何退出路径 (含 panic) 都不会删除该目录
+                let home = temp_dir.keep();
+                info!(session_id = %req.session_id, path = %home.display(), "keep_scratch is set: scratch directory will be retained");
+                (None, home)
+            } else {
+                let home = temp_dir.path().to_path_buf();
+                (Some(temp_dir), home)
+            }
+        }
+    };
+    let TaskPaths { codex_home, work_dir, persistent_dir, strace_log } = resolve_task_paths(&req, &config, &scratch)?;
+    create_dir_all(&codex_home).await?;
+    if !req.session_namespace.is_empty() { register_namespace_session(&codex_home, &req.session_id).await?; }
+    let codex_home = codex_home.as_path();
+    if req.expand_env_var_templates {
+        let vars = [
+            ("session_id", req.session_id.clone()),
+            ("timestamp_unix", chrono::Utc::now().timestamp().to_string()),
+            ("work_dir", work_dir.display().to_string()),
+            ("codex_home", codex_home.display().to_string()),
+        ];
+        for value in req.env_vars.values_mut() {
+            *value = expand_env_template(value, &vars);
+        }
+    }
+    let init_timeout = match req.workspace_init_timeout_secs {
+        0 => DEFAULT_WORKSPACE_INIT_TIMEOUT_SECS,
+        secs => u64::from(secs),
+    };
+    let phase = std::sync::Mutex::new("create_dirs");
+    let prepared = tokio::time::timeout(
+        Duration::from_secs(init_timeout),
+        prepare_workspace(&mut req, &config, codex_home, &work_dir, &redactor, &tx, &phase),
+    ).await;
+    match prepared {
+        Ok(result) => result?,
+        Err(_) => {
+            let phase = phase.lock().map_or("unknown", |p| *p);
+            // 临时工作区整体删除；客户端提供的 base_dir 不归 Adapter 所有，保留原样
+            if let Some(temp_dir) = temp_dir { let _ = temp_dir.close(); }
+            return Err(AdapterError::DeadlineExceeded(format!(
+                "workspace initialization timed out after {init_timeout}s during phase {phase}"
+            )));
         }
     }
 
-    // 等待子进程退出并提取最终“灵魂”
-    let status = child.wait().await?;
-    if status.success() {
-        if let Some(data) = extract_updated_rollout(codex_home, session_id).await? {
-            info!(bytes = data.len(), "Captured updated session rollout");
-            let _ = tx.send(Ok(RunTaskResponse {
-                event: Some(agent::run_task_response::Event::UpdatedRollout(data))
-            })).await;
+    // 设置了 TTL 的持久化工作区 (base_dir) 登记到 GC，过期后自动清理
+    let workspace_lease = match &persistent_dir {
+        Some(dir) => Some(WorkspaceLease::acquire(dir, &req.session_id, u64::from(req.workspace_persistence_ttl_secs)).await?),
+        None => None,
+    };
+
+    // 在 codex 启动前开始监视，任务结束 (watcher 被 drop) 时停止
+    let _watcher = if req.workspace_file_watcher_events {
+        Some(watcher::WorkspaceWatcher::spawn(&work_dir, tx.clone())?)
+    } else {
+        None
+    };
+
+    // 5. 构建并启动 Codex 子进程
+    if let Some(path) = &strace_log {
+        create_dir_all(&config.strace_log_dir).await?;
+        warn!(session_id = %req.session_id, path = %path.display(), "ENABLE_STRACE_DEBUG is set: running codex under strace");
+    }
+    let session_log = config.log_dir.as_deref().and_then(|dir| match SessionLog::open(dir, &sanitize_file_component(&req.session_id)) {
+        Ok(log) => {
+            info!(session_id = %req.session_id, path = %log.path().display(), "Writing session log");
+            Some(log)
+        }
+        Err(e) => {
+            warn!(session_id = %req.session_id, "Failed to open session log in AGENT_LOG_DIR: {e}");
+            None
+        }
+    });
+    let first_event_timeout = match req.first_event_timeout_secs {
+        0 => DEFAULT_FIRST_EVENT_TIMEOUT_SECS,
+        secs => u64::from(secs),
+    };
+    let event_filter: Vec<CodexEventKind> = req.event_filter().filter(|k| *k != CodexEventKind::Unspecified).collect();
+    let transient_error = match req.max_retries {
+        0 => None,
+        _ => Some(regex::Regex::new(&config.transient_error_pattern).map_err(|e| anyhow::anyhow!("invalid TRANSIENT_ERROR_PATTERN: {e}"))?),
+    };
+    let prompt = build_full_prompt(&req.prompt, req.session_config.as_ref());
+    let mut resume_id = (!req.history_rollout.is_empty()).then(|| req.session_id.clone());
+    let mut attempt = 0;
+    // 上一次尝试已产生 rollout (其中含本次 prompt) 时为 true
+    let mut continuing = false;
+    let exit_code = loop {
+        let rollout_stream = if req.stream_rollout { Some(RolloutStreamer::spawn(codex_home, redactor.clone(), tx.clone()).await) } else { None };
+        let mut cmd = build_codex_command(&req, &config, codex_home, &work_dir, &trace, strace_log.as_deref(), resume_id.as_deref());
+        let mut child = cmd.spawn().map_err(|e| match e.kind() {
+            std::io::ErrorKind::NotFound => AdapterError::BinaryMissing(PathBuf::from(cmd.as_std().get_program())),
+            _ => AdapterError::Io(e),
+        })?;
+        // 在读取任何 codex 输出之前发送，保证是本次启动的第一个事件
+        let started = TaskStarted {
+            model: req.session_config.as_ref().map(|c| c.model.clone()).unwrap_or_default(),
+            model_provider: req.session_config.as_ref().map(|c| c.model_provider.clone()).unwrap_or_default(),
+            sandbox_policy: req.session_config.as_ref().map_or(0, |c| c.sandbox_policy),
+            work_dir: work_dir.display().to_string(),
+            resume: resume_id.is_some(),
+            attempt,
+        };
+        let _ = tx.send(Ok(event_response(Event::TaskStarted(started)))).await;
+
+        // 注入 Prompt (stdin_null_mode 下 stdin 为空设备，由 codex 自行读取 prompt 来源)
+        if let Some(stdin) = child.stdin.take().filter(|_| !req.stdin_null_mode) {
+            let prompt = if continuing { RETRY_CONTINUATION_PROMPT.to_string() } else { prompt.clone() };
+            spawn_stdin_writer(stdin, prompt, tx.clone());
+        }
+
+        // 6. 实时流处理与灵魂提取
+        let capture = capture::capture_strategy(&req.output_capture_mode, tx.clone(), codex_home, req.typed_events).await?;
+        let options = StreamOptions {
+            session_id: &req.session_id,
+            completion,
+            first_event_timeout: Duration::from_secs(first_event_timeout),
+            parse_events: req.parse_events,
+            typed_events: req.typed_events,
+            redactor: &redactor,
+            // 聚合模式需在内存中缓存全部事件，未设置上限时使用默认上限兜底
+            stdout_limit_bytes: effective_limit(req.subprocess_stdout_limit_bytes, config.max_subprocess_stdout_bytes)
+                .or(req.aggregate_response.then_some(DEFAULT_AGGREGATE_STDOUT_LIMIT_BYTES)),
+            budget_tokens: (req.session_budget_tokens > 0).then_some(u64::from(req.session_budget_tokens)),
+            warn_after: (req.warn_after_secs > 0).then(|| Duration::from_secs(u64::from(req.warn_after_secs))),
+            task_timeout: match req.timeout_sec {
+                0 => config.default_task_timeout_secs.map(Duration::from_secs),
+                secs => Some(Duration::from_secs(u64::from(secs))),
+            },
+            shutdown: &shutdown,
+            cancel: &cancel,
+            session_log: session_log.as_ref(),
+            heartbeat_interval: (config.heartbeat_interval_secs > 0).then(|| Duration::from_secs(config.heartbeat_interval_secs)),
+            event_filter: &event_filter,
+            // 最后一次尝试按常规失败处理 (上报 rollout、AgentError 与 TaskComplete)
+            transient_error: transient_error.as_ref().filter(|_| attempt < req.max_retries),
+            rollout_stream: rollout_stream.as_ref(),
+            stderr_limits: StderrLimits {
+                max_lines: (config.max_stderr_lines > 0).then_some(config.max_stderr_lines),
+                max_bytes: (config.max_stderr_bytes > 0).then_some(config.max_stderr_bytes),
+            },
+            resource_limits: ResourceLimits::from_request(&req),
+            cancel_grace: Duration::from_secs(config.cancel_grace_secs),
+        };
+        let (exit_code, reason, codex_session_id) = match process_streams(child, tx.clone(), capture, codex_home, &options).await? {
+            StreamExit::Finished(exit_code) => break exit_code,
+            StreamExit::Transient { exit_code, reason, codex_session_id } => (exit_code, reason, codex_session_id),
+        };
+
+        // 7. 临时错误：指数退避后 resume 已产生的 rollout 重试 (尚无 rollout 时重新开始)
+        attempt += 1;
+        let delay = retry_backoff(config.retry_backoff_base_ms, attempt);
+        warn!(session_id = %req.session_id, exit_code, attempt, max_retries = req.max_retries, "codex hit a transient error: {reason}");
+        let _ = tx.send(Ok(event_response(Event::AdapterLog(format!(
+            "codex exited with code {exit_code} on a transient error ({reason}); retrying in {}ms (attempt {attempt}/{})",
+            delay.as_millis(),
+            req.max_retries
+        ))))).await;
+        tokio::select! {
+            _ = tokio::time::sleep(delay) => {}
+            _ = cancel.cancelled() => {
+                let _ = tx.send(Ok(event_response(Event::Cancelled(Cancelled { forced: false })))).await;
+                let _ = tx.send(Ok(event_response(Event::TaskComplete(task_complete(exit_code, false, completion))))).await;
+                break Some(exit_code);
+            }
+            _ = shutdown.cancelled() => {
+                let _ = tx.send(Ok(event_response(Event::TaskComplete(task_complete(exit_code, false, completion))))).await;
+                break Some(exit_code);
+            }
+        }
+        if codex_session_id.is_some() {
+            resume_id = codex_session_id;
+            continuing = true;
         }
+    };
+
+    if let Some(lease) = &workspace_lease
+        && let Err(e) = lease.touch().await
+    {
+        warn!("Failed to refresh workspace metadata: {e}");
     }
-    Ok(())
+    Ok(exit_code)
 }
 
-async fn extract_updated_rollout(home: &Path, _id: &str) -> anyhow::Result<Option<Vec<u8>>> {
-    let root = home.join("sessions");
-    if !root.exists() { return Ok(None); }
-    let mut latest: Option<(std::time::SystemTime, PathBuf)> = None;
-    
-    // 递归寻找最新的 .jsonl 文件，不再校验 ID
-    fn walk(dir: &Path, latest: &mut Option<(std::time::SystemTime, PathBuf)>) -> anyhow::Result<()> {
-        if !dir.is_dir() { return Ok(()); }
-        for entry in std::fs::read_dir(dir)? {
-            let entry = entry?;
-            let path = entry.path();
-            if path.is_dir() { 
-                walk(&path, latest)?; 
-            } else if path.extension().map_or(false, |ext| ext == "jsonl") {
-                let mtime = entry.metadata()?.modified()?;
-                if latest.as_ref().map_or(true, |(t, _)| mtime > *t) {
-                    *latest = Some((mtime, path));
-                }
+// 实际运行与 dry_run 共用的路径解析，不创建任何目录
+struct TaskPaths {
+    // 已包含 session_namespace
+    codex_home: PathBuf,
+    work_dir: PathBuf,
+    // 设置了 workspace_persistence_ttl_secs 时规范化后的 base_dir
+    persistent_dir: Option<PathBuf>,
+    strace_log: Option<PathBuf>,
+}
+
+// scratch 为未配置 CODEX_HOME_TEMPLATE 时使用的临时 codex home
+fn resolve_task_paths(req: &RunTaskRequest, config: &ServerConfig, scratch: &Path) -> Result<TaskPaths, AdapterError> {
+    let codex_home = match &config.codex_home_template {
+        Some(template) => render_codex_home(template, config.codex_home_root.as_deref(), req)?,
+        None => scratch.to_path_buf(),
+    };
+    let codex_home = match req.session_namespace.as_str() {
+        "" => codex_home,
+        ns => namespace_home(&codex_home, ns),
+    };
+    let work_dir = if req.base_dir.is_empty() { codex_home.join("workspace") } else { PathBuf::from(&req.base_dir) };
+    // GC 会删除整个目录，须在写入任何文件之前确认 base_dir 位于服务端的持久化根目录之下
+    let persistent_dir = if !req.base_dir.is_empty() && req.workspace_persistence_ttl_secs > 0 {
+        Some(workspace_gc::persistent_workspace_path(config.persistent_workspace_root.as_deref(), &work_dir)?)
+    } else {
+        None
+    };
+    let strace_log = config.enable_strace_debug.then(|| config.strace_log_dir.join(format!("{}.strace.log", sanitize_file_component(&req.session_id))));
+    Ok(TaskPaths { codex_home, work_dir, persistent_dir, strace_log })
+}
+
+// 工作区初始化 (目录、历史会话、配置、上下文文件)，phase 记录当前所处阶段以便超时时报告
+async fn prepare_workspace(req: &mut RunTaskRequest, config: &ServerConfig, codex_home: &Path, work_dir: &Path, redactor: &RolloutRedactor, tx: &EventSender, phase: &std::sync::Mutex<&'static str>) -> Result<(), AdapterError> {
+    create_dir_all(work_dir).await?;
+    warn_if_low_disk_space(work_dir);
+    for file in &mut req.context_files {
+        file.path = normalize_context_file_path(&file.path);
+    }
+
+    // 2. 灵魂复活逻辑 (State Revival)
+    let is_resuming = !req.history_rollout.is_empty();
+    if is_resuming {
+        set_phase(phase, "history_rollout");
+        if config.validate_rollout_on_resume {
+            let info = rollout::validate_rollout(&req.history_rollout)?;
+            info!(session_id = %req.session_id, rollout_session_id = %info.session_id, lines = info.lines, "Validated history rollout");
+            if info.session_id != req.session_id {
+                warn!(session_id = %req.session_id, rollout_session_id = %info.session_id, "history_rollout belongs to a different session id");
+                let note = format!("history_rollout records session id {:?}, request session_id is {:?}", info.session_id, req.session_id);
+                let _ = tx.send(Ok(event_response(Event::AdapterLog(note)))).await;
             }
         }
-        Ok(())
+        // 放在 rollout 创建当天的目录并沿用 codex 的文件命名，`codex exec resume` 才能找到它
+        let history_file = codex_home.join(rollout::revival_path(&req.history_rollout, &req.session_id, chrono::Local::now()));
+        if let Some(dir) = history_file.parent() { create_dir_all(dir).await?; }
+        let (history, redacted) = redactor.redact(&req.history_rollout);
+        if redacted > 0 { info!(redacted_lines = redacted, "Redacted history rollout before replay"); }
+        write_file(&history_file, &history).await?;
+        info!(session_id = %req.session_id, "Revived session state");
     }
-    
-    walk(&root, &mut latest)?;
-    if let Some((_, p)) = latest { 
-        info!(path = %p.display(), "Extracted latest rollout file");
-        Ok(Some(tokio::fs::read(p).await?)) 
-    } else { 
-        Ok(None) 
+
+    // 3. 动态配置注入
+    if let Some(config) = &mut req.session_config {
+        set_phase(phase, "config");
+        if let Some(prov) = &mut config.provider_info {
+            apply_provider_defaults(prov);
+            if let Some(key) = &prov.env_key
+                && let Some(val) = req.env_vars.get(key)
+            {
+                prov.experimental_bearer_token = Some(val.clone());
+            }
+        }
+        let toml = CONFIG_CACHE.get_or_generate(config, generate_config_toml)?;
+        if config.history_persistence() == HistoryPersistence::None {
+            let _ = tx.send(Ok(event_response(Event::AdapterLog(
+                "history_persistence is HISTORY_PERSISTENCE_NONE: codex keeps no session history, so no UpdatedRollout will be returned".to_string(),
+            )))).await;
+        }
+        let config_path = codex_home.join("config.toml");
+        // 持久化 codex home 上配置未变化时保留原文件，减少频繁恢复会话的写入
+        let unchanged = req.persist_config_file
+            && tokio::fs::read(&config_path).await.is_ok_and(|existing| Sha256::digest(&existing) == Sha256::digest(toml.as_bytes()));
+        if unchanged {
+            debug!(path = %config_path.display(), "config.toml unchanged, skipping rewrite");
+        } else {
+            write_file(&config_path, toml).await?;
+        }
+    }
+
+    // 共享工作区：以写时复制方式叠加到 work_dir，本任务的修改不影响共享快照
+    if !req.shared_workspace_id.is_empty() {
+        set_phase(phase, "shared_workspace");
+        let source = shared_workspace_path(config, &req.shared_workspace_id)?;
+        let target = work_dir.to_path_buf();
+        let copied = tokio::task::spawn_blocking(move || copy_tree_cow(&source, &target))
+            .await
+            .map_err(anyhow::Error::from)??;
+        info!(shared_workspace_id = %req.shared_workspace_id, files = copied, "Cloned shared workspace");
+    }
+
+    // 持久化工作区写入说明文件，便于事后了解其来源；先于上下文文件写入，客户端可主动覆盖
+    if !req.base_dir.is_empty() {
+        set_phase(phase, "readme");
+        write_file(&work_dir.join(WORKSPACE_README_NAME), render_workspace_readme(req)).await?;
+    }
+
+    // 4. 注入上下文文件
+    set_phase(phase, "context_files");
+    if req.context_file_diff_mode {
+        let stats = inject_context_files_diff(&req.context_files, work_dir, tx).await?;
+        info!(written = stats.files_written, skipped = stats.files_skipped, deleted = stats.files_deleted, "Injected context files (diff mode)");
+        let _ = tx.send(Ok(event_response(Event::ContextInjectionDiffStats(stats)))).await;
+    } else {
+        for file in &req.context_files {
+            let Some(path) = safe_join(work_dir, &file.path) else {
+                reject_context_file(tx, &file.path).await;
+                continue;
+            };
+            write_context_file(&path, file).await?;
+        }
     }
+    verify_context_checksums(&req.context_files, work_dir).await?;
+
+    Ok(())
 }
 
-fn build_full_prompt(prompt: &str, config: Option<&SessionConfig>) -> String {
-    let mut p = Vec::new();
-    if let Some(c) = config {
-        if let Some(s) = &c.instructions { p.push(s.clone()); }
-        if let Some(d) = &c.developer_instructions { p.push(d.clone()); }
-    }
-    p.push(prompt.to_string());
-    p.join("\n\n")
-}
-
-fn generate_config_toml(config: &SessionConfig) -> anyhow::Result<String> {
-    let mut toml = String::new();
-    toml.push_str("model_auto_compact_token_limit = 100000\n[history]\npersistence = \"save-all\"\n\n");
-    if !config.model.is_empty() { toml.push_str(&format!("model = {:?}\n", config.model)); }
-    if !config.model_provider.is_empty() { toml.push_str(&format!("model_provider = {:?}\n", config.model_provider)); }
-    if let Some(instr) = &config.instructions { toml.push_str(&format!("instructions = {:?}\n", instr)); }
-    if let Some(dev) = &config.developer_instructions { toml.push_str(&format!("developer_instructions = {:?}\n", dev)); }
-    
-    if let Some(provider) = &config.provider_info {
-        toml.push_str(&format!("\n[model_providers.{}]\nname = {:?}\n", provider.name, provider.name));
-        if let Some(url) = &provider.base_url { toml.push_str(&format!("base_url = {:?}\n", url)); }
-        let wire = match WireApi::try_from(provider.wire_api).unwrap_or(WireApi::Chat) {
-            WireApi::Chat => "chat",
-            WireApi::Responses => "responses",
-            WireApi::ResponsesWebsocket => "responses_websocket",
-        };
-        toml.push_str(&format!("wire_api = {:?}\n", wire));
-        if let Some(tok) = &provider.experimental_bearer_token {
-            toml.push_str(&format!("experimental_bearer_token = {:?}\n", tok));
-        } else if let Some(key) = &provider.env_key {
-            toml.push_str(&format!("env_key = {:?}\n", key));
-        }
-        toml.push_str(&format!("requires_openai_auth = {}\n", provider.requires_openai_auth));
+// 客户端未携带 API Key 时使用服务端环境中的密钥，使 env_key 对应的 bearer token 注入生效
+fn inject_server_api_keys(env_vars: &mut std::collections::HashMap<String, String>, config: &ServerConfig) {
+    let keys = [
+        ("OPENAI_API_KEY", config.auto_inject_openai_api_key),
+        ("ANTHROPIC_API_KEY", config.auto_inject_anthropic_api_key),
+    ];
+    for (key, enabled) in keys {
+        if !enabled || env_vars.contains_key(key) { continue; }
+        if let Ok(value) = std::env::var(key)
+            && !value.is_empty()
+        {
+            env_vars.insert(key.to_string(), value);
+        }
     }
-    
-    if !config.mcp_servers.is_empty() {
-        for (name, def) in &config.mcp_servers {
-            toml.push_str(&format!("\n[mcp_servers.{}]\ntype = {:?}\n", name, def.server_type));
-            if !def.command.is_empty() { toml.push_str(&format!("command = {:?}\n", def.command)); }
-            if !def.url.is_empty() { toml.push_str(&format!("url = {:?}\n", def.url)); }
+}
+
+// 替换 {name} 形式的占位符；未知占位符原样保留
+fn expand_env_template(value: &str, vars: &[(&str, String)]) -> String {
+    let mut out = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('{') {
+        let Some(len) = rest[start..].find('}') else { break };
+        out.push_str(&rest[..start]);
+        let name = &rest[start + 1..start + len];
+        match vars.iter().find(|(k, _)| *k == name) {
+            Some((_, v)) => out.push_str(v),
+            None => {
+                debug!(placeholder = name, "Leaving unknown env var placeholder as-is");
+                out.push_str(&rest[start..=start + len]);
+            }
         }
+        rest = &rest[start + len + 1..];
     }
-    Ok(toml)
+    out.push_str(rest);
+    out
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    tracing_subscriber::fmt().with_env_filter("info").init();
-    let addr = "0.0.0.0:50051".parse()?;
-    let adapter = MyAgentService::default();
-    info!("Codex Agent Service listening on {}", addr);
-    Server::builder().add_service(AgentServiceServer::new(adapter)).serve(addr).await?;
+fn namespace_home(codex_home: &Path, ns: &str) -> PathBuf {
+    codex_home.join("namespaces").join(ns)
+}
+
+// 在命名空间索引中登记会话 (已登记的会话不重复追加)，供列举时免去遍历 rollout
+async fn register_namespace_session(ns_home: &Path, session_id: &str) -> Result<(), AdapterError> {
+    let index_path = ns_home.join(NAMESPACE_INDEX_NAME);
+    let existing = match tokio::fs::read_to_string(&index_path).await {
+        Ok(text) => text,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(error::map_io_error(e, &index_path)),
+    };
+    let registered = existing
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .any(|entry| entry["session_id"] == session_id);
+    if registered { return Ok(()); }
+
+    let mut line = serde_json::json!({ "session_id": session_id, "created_at": chrono::Utc::now().to_rfc3339() }).to_string();
+    line.push('\n');
+    let mut file = tokio::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&index_path)
+        .await
+        .map_err(|e| error::map_io_error(e, &index_path))?;
+    file.write_all(line.as_bytes()).await.map_err(|e| error::map_io_error(e, &index_path))?;
     Ok(())
-}
\ No newline at end of file
+}
+
+fn set_phase(phase: &std::sync::Mutex<&'static str>, name: &'static str) {
+    if let Ok(mut p) = phase.lock() { *p = name; }
+}
+
+// 渲染 CODEX_HOME_TEMPLATE ({session_id} / {tenant_id} / {date})，结果必须位于 CODEX_HOME_ROOT 之内
+fn render_codex_home(template: &str, root: Option<&Path>, req: &RunTaskRequest) -> Result<PathBuf, AdapterError> {
+    let Some(root) = root else {
+        return Err(anyhow::anyhow!("CODEX_HOME_TEMPLATE is set but CODEX_HOME_ROOT is not").into());
+    };
+    let tenant_id = if req.tenant_id.is_empty() { "default" } else { req.tenant_id.as_str() };
+    let rendered = template
+        .replace("{session_id}", &sanitize_file_component(&req.session_id))
+        .replace("{tenant_id}", &sanitize_file_component(tenant_id))
+        .replace("{date}", &chrono::Utc::now().format("%Y-%m-%d").to_string());
+    let rendered = PathBuf::from(rendered);
+    let path = if rendered.is_absolute() { rendered } else { root.join(rendered) };
+    let escapes = path.components().any(|c| matches!(c, std::path::Component::ParentDir));
+    if escapes || !path.starts_with(root) || path == root {
+        return Err(AdapterError::InvalidArgument(format!(
+            "codex home {} resolves outside CODEX_HOME_ROOT {}",
+            path.display(),
+            root.display()
+        )));
+    }
+    Ok(path)
+}
+
+fn render_workspace_readme(req: &RunTaskRequest) -> String {
+    let (model, provider) = req.session_config.as_ref().map_or(("", ""), |c| (c.model.as_str(), c.model_provider.as_str()));
+    let mut readme = format!(
+        "# Codex workspace\n\n- session_id: {}\n- created_at: {}\n- model: {model}\n- provider: {provider}\n\n## Context files\n\n",
+        req.session_id,
+        chrono::Utc::now().to_rfc3339(),
+    );
+    for file in &req.context_files {
+        readme.push_str(&format!("- `{}` ({} bytes)\n", file.path, file.content.len()));
+    }
+    readme.push_str(&format!("\n## Prompt\n\n{}\n", events::truncate(&req.prompt, 200)));
+    readme
+}
+
+fn shared_workspace_path(config: &ServerConfig, id: &str) -> Result<PathBuf, AdapterError> {
+    if !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(AdapterError::InvalidArgument(format!("invalid shared_workspace_id {id:?}")));
+    }
+    let Some(root) = &config.shared_workspace_root else {
+        return Err(AdapterError::NotFound(format!("shared workspace {id:?} not found: SHARED_WORKSPACE_ROOT is not set")));
+    };
+    let path = root.join(id);
+    if !path.is_dir() {
+        return Err(AdapterError::NotFound(format!("shared workspace {id:?} not found at {}", path.display())));
+    }
+    Ok(path)
+}
+
+// 递归复制目录树；支持 reflink 的文件系统上为写时复制，否则退化为普通复制
+fn copy_tree_cow(source: &Path, target: &Path) -> Result<usize, AdapterError> {
+    let mut copied = 0;
+    for entry in walkdir::WalkDir::new(source).follow_links(false) {
+        let entry = entry.map_err(anyhow::Error::from)?;
+        let Ok(rel) = entry.path().strip_prefix(source) else { continue };
+        let dest = target.join(rel);
+        let file_type = entry.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&dest).map_err(|e| error::map_io_error(e, &dest))?;
+        } else if file_type.is_file() {
+            reflink_copy::reflink_or_copy(entry.path(), &dest).map_err(|e| error::map_io_error(e, &dest))?;
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+// 单个上下文文件与本次请求全部上下文文件的大小上限，在写入任何文件之前检查
+fn check_context_file_sizes(files: &[File], max_file_bytes: u64, max_total_bytes: u64) -> Result<(), AdapterError> {
+    let mut total: u64 = 0;
+    for file in files {
+        let size = file.content.len() as u64;
+        if size > max_file_bytes {
+            return Err(AdapterError::InvalidArgument(format!(
+                "context file {:?} is {size} bytes, exceeding the per-file limit of {max_file_bytes} bytes (MAX_CONTEXT_FILE_BYTES)",
+                file.path
+            )));
+        }
+        total += size;
+        if total > max_total_bytes {
+            return Err(AdapterError::InvalidArgument(format!(
+                "context file {:?} brings the total context size to {total} bytes, exceeding the limit of {max_total_bytes} bytes (MAX_CONTEXT_BYTES)",
+                file.path
+            )));
+        }
+    }
+    Ok(())
+}
+
+// 持有本任务计入 TOTAL_WORKSPACE_BYTES 的字节数，任务结束时归还
+struct WorkspaceBytesGuard(u64);
+
+impl WorkspaceBytesGuard {
+    fn reserve(bytes: u64, limit: Option<u64>) -> Result<Self, AdapterError> {
+        let reserved = TOTAL_WORKSPACE_BYTES.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |total| {
+            let next = total.checked_add(bytes)?;
+            match limit {
+                Some(max) if next > max => None,
+                _ => Some(next),
+            }
+        });
+        match reserved {
+            Ok(_) => Ok(Self(bytes)),
+            Err(total) => Err(AdapterError::ResourceExhausted(format!(
+                "total workspace limit exceeded: {total} bytes in use by active sessions, request adds {bytes}, limit {}",
+                limit.unwrap_or(u64::MAX)
+            ))),
+        }
+    }
+}
+
+impl Drop for WorkspaceBytesGuard {
+    fn drop(&mut self) {
+        TOTAL_WORKSPACE_BYTES.fetch_sub(self.0, Ordering::SeqCst);
+    }
+}
+
+// 写入后从磁盘重新读取每个上下文文件，与客户端提供的 SHA-256 (为空则跳过) 比对，列出所有不一致的文件
+async fn verify_context_checksums(files: &[File], work_dir: &Path) -> Result<(), AdapterError> {
+    let mut mismatched = Vec::new();
+    for file in files.iter().filter(|f| !f.checksum.is_empty()) {
+        // 被拒绝的路径未写入，已单独上报
+        let Some(path) = safe_join(work_dir, &file.path) else { continue };
+        let on_disk = tokio::fs::read(&path).await.map_err(|e| error::map_io_error(e, &path))?;
+        if !format!("{:x}", Sha256::digest(&on_disk)).eq_ignore_ascii_case(file.checksum.trim()) {
+            mismatched.push(file.path.as_str());
+        }
+    }
+    if mismatched.is_empty() {
+        Ok(())
+    } else {
+        Err(AdapterError::DataLoss(format!("context file checksum mismatch: {}", mismatched.join(", "))))
+    }
+}
+
+// 将任意字符串转为安全的单级文件名 (仅保留字母数字、'-'、'_'、'.')
+fn sanitize_file_component(name: &str) -> String {
+    let sanitized: String = name.chars().map(|c| if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.') { c } else { '_' }).collect();
+    match sanitized.trim_matches('.') {
+        "" => "unnamed".to_string(),
+        trimmed => trimmed.to_string(),
+    }
+}
+
+// 统一分隔符 (Windows 客户端可能发送反斜杠路径)、合并重复的斜杠并去除首尾空白；
+// 前导 `/` 保留，仍由 is_safe_context_path 拒绝
+fn normalize_context_file_path(p: &str) -> String {
+    let mut normalized = String::with_capacity(p.len());
+    for c in p.trim().chars().map(|c| if c == '\\' { '/' } else { c }) {
+        if c == '/' && normalized.ends_with('/') { continue; }
+        normalized.push(c);
+    }
+    normalized
+}
+
+// 按本平台的路径语义判断：只允许普通组件与 `.`，拒绝根路径、`..` 以及 Windows 盘符/UNC 前缀
+fn is_safe_context_path(path: &str) -> bool {
+    let path = Path::new(path);
+    !path.has_root() && path.components().all(|c| matches!(c, std::path::Component::Normal(_) | std::path::Component::CurDir))
+}
+
+// 将客户端提供的相对路径拼接到 base 下，拒绝任何可能落到 base 之外的路径：
+// 绝对路径、Windows 盘符/UNC 前缀、`..`，以及经由已存在的符号链接 (包括悬空链接) 逃逸的路径
+fn safe_join(base: &Path, rel: &str) -> Option<PathBuf> {
+    if rel.is_empty() || !is_safe_context_path(rel) { return None; }
+    let rel_path = Path::new(rel);
+    let base = std::fs::canonicalize(base).ok()?;
+    let joined = base.join(rel_path);
+
+    // 从目标路径向上找到第一个已存在的条目，解析符号链接后必须仍位于 base 之内
+    let mut existing = joined.as_path();
+    while std::fs::symlink_metadata(existing).is_err() {
+        existing = existing.parent()?;
+    }
+    let resolved = std::fs::canonicalize(existing).ok()?;
+    resolved.starts_with(&base).then_some(joined)
+}
+
+async fn reject_context_file(tx: &EventSender, path: &str) {
+    warn!(path, "Rejected context file path outside the workspace");
+    let message = format!("Skipped context file {path:?}: path escapes the workspace");
+    let _ = tx.send(Ok(event_response(Event::AdapterLog(message)))).await;
+}
+
+// 按原始字节写入上下文文件，再应用请求的权限位
+async fn write_context_file(path: &Path, file: &File) -> Result<(), AdapterError> {
+    if let Some(parent) = path.parent() { create_dir_all(parent).await?; }
+    write_file(path, &file.content).await?;
+    set_context_file_mode(path, file.mode).await
+}
+
+#[cfg(unix)]
+async fn set_context_file_mode(path: &Path, mode: Option<u32>) -> Result<(), AdapterError> {
+    use std::os::unix::fs::PermissionsExt;
+    let Some(mode) = mode else { return Ok(()) };
+    tokio::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).await?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn set_context_file_mode(_path: &Path, _mode: Option<u32>) -> Result<(), AdapterError> {
+    Ok(())
+}
+
+// 增量注入：仅写入哈希与上次清单不同的文件，删除本次请求中已不存在的文件
+async fn inject_context_files_diff(files: &[File], work_dir: &Path, tx: &EventSender) -> Result<ContextInjectionDiffStats, AdapterError> {
+    let manifest_path = work_dir.join(FILE_MANIFEST_NAME);
+    // 清单只用于找出上次写入、本次不再需要的文件；是否跳过写入以磁盘上的实际内容为准
+    let previous: BTreeMap<String, String> = match tokio::fs::read(&manifest_path).await {
+        Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+            warn!(path = %manifest_path.display(), error = %e, "Context file manifest is corrupt; stale files from the previous run will not be deleted");
+            BTreeMap::new()
+        }),
+        Err(_) => BTreeMap::new(),
+    };
+
+    let mut stats = ContextInjectionDiffStats::default();
+    let mut current = BTreeMap::new();
+    for file in files {
+        let Some(path) = safe_join(work_dir, &file.path) else {
+            reject_context_file(tx, &file.path).await;
+            continue;
+        };
+        let hash = format!("{:x}", Sha256::digest(&file.content));
+        // codex 可能在上次运行中改写了文件，与清单中的哈希一致并不代表磁盘内容未变
+        let on_disk = tokio::fs::read(&path).await.ok().map(|bytes| format!("{:x}", Sha256::digest(bytes)));
+        if on_disk.as_ref() == Some(&hash) {
+            // 内容未变但权限可能已调整
+            set_context_file_mode(&path, file.mode).await?;
+            stats.files_skipped += 1;
+        } else {
+            write_context_file(&path, file).await?;
+            stats.files_written += 1;
+        }
+        current.insert(file.path.clone(), hash);
+    }
+
+    for stale in previous.keys().filter(|p| !current.contains_key(*p)) {
+        let Some(stale_path) = safe_join(work_dir, stale) else { continue };
+        match tokio::fs::remove_file(stale_path).await {
+            Ok(()) => stats.files_deleted += 1,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    // 先写临时文件再 rename，保证清单原子更新
+    let tmp_path = work_dir.join(format!("{FILE_MANIFEST_NAME}.tmp"));
+    write_file(&tmp_path, serde_json::to_vec_pretty(&current).map_err(anyhow::Error::from)?).await?;
+    tokio::fs::rename(&tmp_path, &manifest_path).await?;
+    Ok(stats)
+}
+
+// 工作根目录所在文件系统剩余空间不足 10% 时告警
+#[cfg(unix)]
+#[allow(clippy::unnecessary_cast)]
+fn warn_if_low_disk_space(path: &Path) {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(c_path) = std::ffi::CString::new(path.as_os_str().as_bytes()) else { return };
+    // SAFETY: statvfs 只写入我们提供的结构体，c_path 在调用期间保持有效
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 { return; }
+    let total = stat.f_blocks as u64 * stat.f_frsize as u64;
+    let free = stat.f_bavail as u64 * stat.f_frsize as u64;
+    if total > 0 && free.saturating_mul(10) < total {
+        warn!(path = %path.display(), free_bytes = free, total_bytes = total, "Work root filesystem has less than 10% free space");
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_low_disk_space(_path: &Path) {}
+
+// resume_id 为 Some 时以 `exec resume <id>` 续跑已有会话 (历史会话复活或临时错误重试)
+fn build_codex_command(req: &RunTaskRequest, config: &ServerConfig, codex_home: &Path, work_dir: &Path, trace: &TraceContext, strace_log: Option<&Path>, resume_id: Option<&str>) -> Command {
+    let codex_bin = &config.codex_bin;
+    // 调试模式下以 strace 包裹 codex，输出写入会话独立的日志文件而非经由 Adapter 转发
+    let mut cmd = match strace_log {
+        Some(log) => {
+            let mut cmd = Command::new("strace");
+            cmd.arg("-f").arg("-e").arg("trace=file,process").arg("-o").arg(log).arg(codex_bin);
+            cmd
+        }
+        None => Command::new(codex_bin),
+    };
+    
+    // 配置全局覆盖参数 (必须在子命令前)
+    if let Some(config) = &req.session_config {
+        if !config.model.is_empty() {
+            cmd.arg("-c").arg(format!("model={}", config.model));
+        }
+        if !config.model_provider.is_empty() {
+            cmd.arg("-c").arg(format!("model_provider={}", config.model_provider));
+        }
+        if !config.model_reasoning_effort.is_empty() {
+            cmd.arg("-c").arg(format!("model_reasoning_effort={}", config.model_reasoning_effort));
+        }
+        if !config.model_verbosity.is_empty() {
+            cmd.arg("-c").arg(format!("model_verbosity={}", config.model_verbosity));
+        }
+        if let Some(policy) = approvals::policy_value(config.approval_policy()) {
+            cmd.arg("-c").arg(format!("approval_policy={policy}"));
+        }
+        // 仅在 WORKSPACE_WRITE 沙箱下生效
+        if config.sandbox_policy() == SandboxPolicy::WorkspaceWrite {
+            if config.network_access {
+                cmd.arg("-c").arg("sandbox_workspace_write.network_access=true");
+            }
+            if !config.writable_roots.is_empty() {
+                // 以 TOML 数组传值，路径中的引号与反斜杠由序列化器转义
+                let roots = toml::Value::Array(config.writable_roots.iter().cloned().map(toml::Value::String).collect());
+                cmd.arg("-c").arg(format!("sandbox_workspace_write.writable_roots={roots}"));
+            }
+        }
+    }
+    // 客户端的原始覆盖放在内置覆盖之后，codex 对同一键以最后一次为准
+    if !req.config_overrides.is_empty() {
+        let overrides: BTreeMap<_, _> = req.config_overrides.iter().collect();
+        info!(session_id = %req.session_id, ?overrides, "Applying client config overrides");
+        for (key, value) in overrides {
+            cmd.arg("-c").arg(format!("{key}={value}"));
+        }
+    }
+
+    cmd.arg("exec").arg("--json").arg("--skip-git-repo-check");
+
+    // 按请求的沙箱策略运行；仅 DangerFullAccess 跳过沙箱与审批，未指定时使用 codex 默认策略
+    if let Some(config) = &req.session_config {
+        match SandboxPolicy::try_from(config.sandbox_policy).unwrap_or(SandboxPolicy::Unspecified) {
+            SandboxPolicy::WorkspaceWrite => { cmd.arg("--sandbox").arg("workspace-write"); },
+            SandboxPolicy::ReadOnly => { cmd.arg("--sandbox").arg("read-only"); },
+            SandboxPolicy::DangerFullAccess => { cmd.arg("--dangerously-bypass-approvals-and-sandbox"); },
+            _ => {}
+        }
+    }
+
+    if let Some(id) = resume_id {
+        cmd.arg("resume").arg(id);
+    }
+
+    if req.stdin_null_mode {
+        cmd.stdin(Stdio::null());
+    } else {
+        cmd.arg("-").stdin(Stdio::piped());
+    }
+
+    // 独立进程组，便于终止时连同 codex 派生的子进程一起清理
+    #[cfg(unix)]
+    cmd.process_group(0);
+    // CREATE_NEW_PROCESS_GROUP：Adapter 收到的 Ctrl+C 不直接传给 codex，由 kill_tree 统一清理
+    #[cfg(windows)]
+    cmd.creation_flags(0x0000_0200);
+
+    // 不继承 Adapter 的环境变量，仅使用 codex_env 给出的变量，避免宿主凭据泄露给模型
+    cmd.env_clear()
+       .envs(codex_env(req, config, codex_home, trace, |name| std::env::var_os(name)))
+       .current_dir(work_dir)
+       .stdout(Stdio::piped())
+       .stderr(Stdio::piped())
+       // 任何提前返回或 panic 导致 Child 被 drop 时都不留下孤儿进程 (任务并发许可随之释放)
+       .kill_on_drop(true);
+
+    #[cfg(unix)]
+    ResourceLimits::from_request(req).apply(&mut cmd);
+
+    cmd
+}
+
+// codex 进程的完整环境 (同名变量后者覆盖前者)：白名单中的宿主变量 (由 host_var 读取)、客户端 env_vars 与 Adapter 注入的变量
+fn codex_env(req: &RunTaskRequest, config: &ServerConfig, codex_home: &Path, trace: &TraceContext, host_var: impl Fn(&str) -> Option<std::ffi::OsString>) -> Vec<(std::ffi::OsString, std::ffi::OsString)> {
+    let mut env: Vec<(std::ffi::OsString, std::ffi::OsString)> = config.env_passthrough.iter().filter_map(|name| Some((name.into(), host_var(name)?))).collect();
+    env.push(("RUST_LOG".into(), "info".into()));
+    env.extend(req.env_vars.iter().map(|(k, v)| (k.into(), v.into())));
+    // 放在客户端 env_vars 之后，保证 CODEX_HOME 与会话标识不被覆盖 (前者指向隔离的会话目录，后者便于崩溃后关联日志)
+    env.push(("CODEX_HOME".into(), codex_home.into()));
+    env.push(("CODEX_ADAPTER_SESSION_ID".into(), req.session_id.clone().into()));
+    env.push(("CODEX_ADAPTER_REQUEST_ID".into(), req.request_id.clone().into()));
+    // 透传 W3C Trace Context，便于将 Adapter 与 codex 的 span 关联
+    if let Some(traceparent) = &trace.traceparent { env.push(("TRACEPARENT".into(), traceparent.into())); }
+    if let Some(tracestate) = &trace.tracestate { env.push(("TRACESTATE".into(), tracestate.into())); }
+    env
+}
+
+// 请求级限制 (0 表示不限制) 与服务端上限取较小者
+fn effective_limit(requested: u64, server_cap: Option<u64>) -> Option<u64> {
+    match (requested, server_cap) {
+        (0, cap) => cap,
+        (r, Some(cap)) => Some(r.min(cap)),
+        (r, None) => Some(r),
+    }
+}
+
+// process_streams 的按任务参数
+struct StreamOptions<'a> {
+    session_id: &'a str,
+    completion: TaskCompletion<'a>,
+    first_event_timeout: Duration,
+    parse_events: bool,
+    // 无法解析为 JSON 的 stdout 行附带一条 adapter_log 说明 (仍原样转发)
+    typed_events: bool,
+    redactor: &'a RolloutRedactor,
+    stdout_limit_bytes: Option<u64>,
+    budget_tokens: Option<u64>,
+    warn_after: Option<Duration>,
+    task_timeout: Option<Duration>,
+    shutdown: &'a CancellationToken,
+    // CancelTask 触发：SIGTERM 后继续读取输出，codex 退出后仍回传 rollout
+    cancel: &'a CancellationToken,
+    // SIGTERM 后等待 codex 退出的时长，超时后 SIGKILL
+    cancel_grace: Duration,
+    // 同时写入 AGENT_LOG_DIR 下的会话日志
+    session_log: Option<&'a SessionLog>,
+    heartbeat_interval: Option<Duration>,
+    // 非空时只转发这些类型的 codex 事件 (error 与无法解析的行始终转发)
+    event_filter: &'a [CodexEventKind],
+    // 仍有重试次数时设置：非零退出且 STDERR 命中该模式时交由调用方重试
+    transient_error: Option<&'a regex::Regex>,
+    // stream_rollout：codex 退出后先发完剩余 delta 再发送 UpdatedRollout
+    rollout_stream: Option<&'a RolloutStreamer>,
+    // 转发给客户端的 STDERR 上限；会话日志与启动失败归类不受影响
+    stderr_limits: StderrLimits,
+    // 用于识别 codex 是否因资源上限被终止
+    resource_limits: ResourceLimits,
+}
+
+// process_streams 的结束方式
+#[derive(Debug, PartialEq)]
+enum StreamExit {
+    // 已发送 TaskComplete，值为 codex 退出码 (被终止或无法得知时为 None)
+    Finished(Option<i32>),
+    // 命中临时错误，未发送 rollout 与 TaskComplete；codex_session_id 为已产生的 rollout 的会话 id，供 resume
+    Transient { exit_code: i32, reason: String, codex_session_id: Option<String> },
+}
+
+async fn process_streams(mut child: tokio::process::Child, tx: EventSender, mut capture: Box<dyn capture::CaptureStrategy>, codex_home: &Path, options: &StreamOptions<'_>) -> Result<StreamExit, AdapterError> {
+    let StreamOptions { session_id, completion, first_event_timeout, parse_events, typed_events, redactor, stdout_limit_bytes, budget_tokens, warn_after, task_timeout, shutdown, cancel, session_log, heartbeat_interval, event_filter, transient_error, rollout_stream, stderr_limits, resource_limits, cancel_grace } = *options;
+    let started = tokio::time::Instant::now();
+    let deadline = task_timeout.map(|t| started + t);
+    let stdout = child.stdout.take().unwrap();
+    let stderr = child.stderr.take().unwrap();
+    
+    let mut out_reader = BufReader::new(stdout).lines();
+    // STDERR 与 STDOUT 在同一循环中读取，事件按到达顺序发出
+    let mut stderr = StderrStream::new(stderr, stderr_limits);
+
+    // 距上一个事件超过 heartbeat_interval 时发送心跳，避免 L7 负载均衡回收静默的流 (包括 codex 启动阶段)
+    let mut heartbeat_seq: u64 = 0;
+    let mut next_heartbeat = heartbeat_interval.map(|i| tokio::time::Instant::now() + i);
+    let mut duration_warning = warn_after.map(|period| tokio::time::interval_at(started + period, period));
+
+    // 首行输出单独设置超时：区分“进程从未产生任何输出”与运行中的正常等待
+    let first_event_deadline = started + first_event_timeout;
+    let first_line = loop {
+        tokio::select! {
+            line = out_reader.next_line() => break line,
+            line = stderr.reader.next_line(), if stderr.open => {
+                stderr.forward(&tx, session_log, line).await;
+            }
+            _ = tokio::time::sleep_until(first_event_deadline) => {
+                kill_tree(&mut child).await;
+                stderr.drain(&tx, session_log).await;
+                if let Ok(status) = child.wait().await {
+                    send_task_complete(&tx, &status, false, completion).await;
+                }
+                return Err(AdapterError::ProcessTimeout(format!(
+                    "no output ever received from codex within {}s of spawn (first-event timeout)",
+                    first_event_timeout.as_secs()
+                )));
+            }
+            // 任务总超时可能短于首行超时，启动阶段同样生效
+            _ = sleep_until_if_set(deadline) => {
+                let secs = task_timeout.unwrap_or_default().as_secs();
+                warn!(session_id, timeout_secs = secs, "Task timed out before the first codex event, killing codex process group");
+                kill_tree(&mut child).await;
+                stderr.drain(&tx, session_log).await;
+                if let Ok(status) = child.wait().await {
+                    send_task_complete(&tx, &status, false, completion).await;
+                }
+                return Err(AdapterError::ProcessTimeout(format!("task timed out after {secs}s before codex produced any output")));
+            }
+            // 服务关闭且宽限期已过：不必等到首行超时
+            _ = shutdown.cancelled() => {
+                warn!(session_id, "Adapter shutting down before the first codex event, killing codex process group");
+                kill_tree(&mut child).await;
+                stderr.drain(&tx, session_log).await;
+                let _ = tx.send(Ok(event_response(Event::Error("adapter is shutting down".to_string())))).await;
+                if let Ok(status) = child.wait().await {
+                    send_task_complete(&tx, &status, false, completion).await;
+                }
+                return Ok(StreamExit::Finished(None));
+            }
+            _ = tx.closed() => {
+                info!(session_id, "Client disconnected before first codex event, killing process");
+                kill_tree(&mut child).await;
+                return Ok(StreamExit::Finished(None));
+            }
+            // 尚无任何输出，也就没有可回传的 rollout
+            _ = cancel.cancelled() => {
+                info!(session_id, "Task cancelled before first codex event, terminating codex process group");
+                terminate(&mut child);
+                let forced = wait_or_kill(&mut child, tokio::time::Instant::now() + cancel_grace).await;
+                stderr.drain(&tx, session_log).await;
+                let _ = tx.send(Ok(event_response(Event::Cancelled(Cancelled { forced })))).await;
+                if let Ok(status) = child.wait().await {
+                    send_task_complete(&tx, &status, false, completion).await;
+                }
+                return Ok(StreamExit::Finished(None));
+            }
+            _ = sleep_until_if_set(next_heartbeat) => {
+                heartbeat_seq += 1;
+                let heartbeat = Heartbeat { sequence: heartbeat_seq, elapsed_secs: started.elapsed().as_secs() as u32 };
+                let _ = tx.send(Ok(event_response(Event::Heartbeat(heartbeat)))).await;
+                next_heartbeat = heartbeat_interval.map(|i| tokio::time::Instant::now() + i);
+            }
+            _ = tick_if_enabled(&mut duration_warning) => {
+                let warning = TaskDurationWarning { elapsed_secs: started.elapsed().as_secs() as u32, events_so_far: 0 };
+                warn!(session_id, elapsed_secs = warning.elapsed_secs, "Task is still waiting for codex's first event past warn_after_secs");
+                let _ = tx.send(Ok(event_response(Event::TaskDurationWarning(warning)))).await;
+            }
+        }
+    };
+
+    // 主循环：转发 STDOUT 中的 JSON 事件
+    let mut commentary = parse_events.then(CommentaryTracker::default);
+    let mut final_message = events::FinalMessageTracker::default();
+    let mut pending = Some(first_line);
+    let mut stdout_bytes: u64 = 0;
+    let mut stdout_limit_hit = false;
+    let mut budget_exceeded = false;
+    let mut timed_out = false;
+    let mut shut_down = false;
+    let mut cancelled = false;
+    // 取消后的 SIGKILL 时刻；forced 表示 codex 未在宽限期内退出
+    let mut kill_deadline: Option<tokio::time::Instant> = None;
+    let mut forced = false;
+    let mut events_so_far: u32 = 0;
+    loop {
+        let next = match pending.take() {
+            Some(line) => line,
+            // next_line 可安全取消，告警计时触发时不会丢失半行输出
+            None => tokio::select! {
+                line = out_reader.next_line() => line,
+                line = stderr.reader.next_line(), if stderr.open => {
+                    stderr.forward(&tx, session_log, line).await;
+                    continue;
+                }
+                // 客户端断开 (流被 drop) 时立即终止，避免 codex 在等待模型响应期间继续消耗 token
+                _ = tx.closed() => {
+                    info!(session_id, "Client disconnected, killing codex process group");
+                    kill_tree(&mut child).await;
+                    return Ok(StreamExit::Finished(None));
+                }
+                // 任务总超时：终止 codex 后仍提取 rollout，便于之后恢复部分完成的会话
+                _ = sleep_until_if_set(deadline) => {
+                    let secs = task_timeout.unwrap_or_default().as_secs();
+                    warn!(session_id, timeout_secs = secs, "Task timed out, killing codex process group");
+                    kill_tree(&mut child).await;
+                    let _ = tx.send(Ok(event_response(Event::Error(format!("task timed out after {secs}s"))))).await;
+                    timed_out = true;
+                    break;
+                }
+                // 服务关闭且宽限期已过：终止 codex，仍尝试回传 rollout
+                _ = shutdown.cancelled() => {
+                    warn!(session_id, "Adapter shutting down, killing codex process group");
+                    kill_tree(&mut child).await;
+                    let _ = tx.send(Ok(event_response(Event::Error("adapter is shutting down".to_string())))).await;
+                    shut_down = true;
+                    break;
+                }
+                _ = cancel.cancelled(), if !cancelled => {
+                    info!(session_id, "Task cancelled, terminating codex process group");
+                    terminate(&mut child);
+                    let _ = tx.send(Ok(event_response(Event::AdapterLog("task cancelled via CancelTask".to_string())))).await;
+                    cancelled = true;
+                    kill_deadline = Some(tokio::time::Instant::now() + cancel_grace);
+                    continue;
+                }
+                _ = sleep_until_if_set(kill_deadline) => {
+                    warn!(session_id, grace_secs = cancel_grace.as_secs(), "codex did not exit within the cancel grace period, killing process group");
+                    kill_tree(&mut child).await;
+                    kill_deadline = None;
+                    forced = true;
+                    continue;
+                }
+                _ = sleep_until_if_set(next_heartbeat) => {
+                    heartbeat_seq += 1;
+                    let heartbeat = Heartbeat { sequence: heartbeat_seq, elapsed_secs: started.elapsed().as_secs() as u32 };
+                    let _ = tx.send(Ok(event_response(Event::Heartbeat(heartbeat)))).await;
+                    next_heartbeat = heartbeat_interval.map(|i| tokio::time::Instant::now() + i);
+                    continue;
+                }
+                _ = tick_if_enabled(&mut duration_warning) => {
+                    let warning = TaskDurationWarning { elapsed_secs: started.elapsed().as_secs() as u32, events_so_far };
+                    warn!(session_id, elapsed_secs = warning.elapsed_secs, "Task is still running past warn_after_secs");
+                    let _ = tx.send(Ok(event_response(Event::TaskDurationWarning(warning)))).await;
+                    continue;
+                }
+            },
+        };
+        let Ok(Some(line)) = next else { break };
+        if let Some(log) = session_log { log.write("[STDOUT]", &line); }
+        events_so_far += 1;
+        next_heartbeat = heartbeat_interval.map(|i| tokio::time::Instant::now() + i);
+        stdout_bytes += line.len() as u64 + 1;
+        if let Some(limit) = stdout_limit_bytes
+            && stdout_bytes > limit
+        {
+            warn!(stdout_bytes, limit, "codex stdout limit exceeded, killing process");
+            kill_tree(&mut child).await;
+            let _ = tx.send(Ok(event_response(Event::Error("stdout limit exceeded".to_string())))).await;
+            stdout_limit_hit = true;
+            break;
+        }
+        // 非 JSON 或无法识别的行照常原样转发
+        let parsed = match serde_json::from_str::<serde_json::Value>(&line) {
+            Ok(event) => Some(event),
+            Err(e) => {
+                if typed_events {
+                    let note = format!("codex stdout line is not valid JSON ({e}), forwarding it as codex_event_json: {}", events::truncate(&line, 200));
+                    let _ = tx.send(Ok(event_response(Event::AdapterLog(note)))).await;
+                }
+                None
+            }
+        };
+        // codex 自身报告的错误同样参与启动失败归类
+        if let Some(message) = parsed.as_ref().and_then(events::error_message) {
+            stderr.remember(message);
+        }
+        let usage = parsed.as_ref().and_then(events::token_usage);
+        let final_message = parsed.as_ref().and_then(|event| final_message.observe(event));
+        let milestones = match (commentary.as_mut(), &parsed) {
+            (Some(tracker), Some(event)) => tracker.observe(event),
+            _ => Vec::new(),
+        };
+        // 超出 token 预算后发送 SIGTERM，继续读取输出直到 codex 退出
+        if let (Some(limit), Some(tracker)) = (budget_tokens, &commentary)
+            && !budget_exceeded
+            && tracker.tokens_used() > limit
+        {
+            let used = tracker.tokens_used();
+            warn!(used, limit, "Session token budget exceeded, terminating codex");
+            terminate(&mut child);
+            let _ = tx.send(Ok(event_response(Event::Error(format!("session token budget exceeded: {used}/{limit}"))))).await;
+            budget_exceeded = true;
+        }
+        // 无法解析的行不受 event_filter 影响，始终转发
+        let forward = event_filter.is_empty() || parsed.as_ref().is_none_or(|event| events::passes_filter(event, event_filter));
+        if forward && !capture.on_line(line, parsed.as_ref()).await? {
+            kill_tree(&mut child).await;
+            return Ok(StreamExit::Finished(None));
+        }
+        if let Some(usage) = usage {
+            let _ = tx.send(Ok(event_response(Event::TokenUsage(usage)))).await;
+        }
+        if let Some(text) = final_message {
+            let _ = tx.send(Ok(event_response(Event::FinalMessage(FinalMessage { text })))).await;
+        }
+        for m in milestones {
+            let _ = tx.send(Ok(event_response(Event::AdapterCommentary(m)))).await;
+        }
+    }
+    if !capture.finish().await? {
+        kill_tree(&mut child).await;
+        return Ok(StreamExit::Finished(None));
+    }
+
+    // STDERR 全部转发后才发送 TaskComplete
+    stderr.drain(&tx, session_log).await;
+
+    // 等待子进程退出并提取最终“灵魂” (因输出/预算超限或超时被终止时同样提取，保留已完成的进度)
+    // 已取消时 stdout 关闭后 codex 仍可能未退出，宽限期剩余部分到期后同样 SIGKILL
+    // 回收前读取 codex 自身的 CPU 时间；终止流程中 (kill_deadline) 不做资源上限归因
+    let cpu_used = if kill_deadline.is_none() { exited_child_cpu_time(&child).await } else { None };
+    if let Some(deadline) = kill_deadline { forced |= wait_or_kill(&mut child, deadline).await; }
+    let status = child.wait().await?;
+    if let Some(streamer) = rollout_stream { streamer.finish().await; }
+    let aborted = stdout_limit_hit || budget_exceeded || timed_out || shut_down || cancelled;
+    if !status.success()
+        && !aborted
+        && let Some(pattern) = transient_error
+        && let Some(reason) = stderr.tail.iter().rev().find(|line| pattern.is_match(line))
+    {
+        // rollout 留在 codex home 中，由重试的 codex 进程 resume
+        let codex_session_id = extract_updated_rollout(codex_home, session_id).await?.and_then(|r| rollout_first_line_session_id(&r.data));
+        return Ok(StreamExit::Transient { exit_code: exit_code(&status), reason: reason.clone(), codex_session_id });
+    }
+    if (status.success() || aborted)
+        && let Some(ExtractedRollout { path, mut data }) = extract_updated_rollout(codex_home, session_id).await?
+    {
+        // 首行 session_meta 中 codex 分配的会话 id，可能与客户端的 session_id 不同
+        let codex_session_id = rollout_first_line_session_id(&data).unwrap_or_else(|| {
+            warn!(path = %path.display(), "Could not parse session id from rollout's first record");
+            String::new()
+        });
+        let metadata = RolloutMetadata {
+            file_name: path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+            codex_session_id,
+        };
+        let _ = tx.send(Ok(event_response(Event::RolloutMetadata(metadata)))).await;
+        if !redactor.is_empty() {
+            let (filtered, redacted) = redactor.redact(&data);
+            info!(redacted_lines = redacted, "Redacted session rollout");
+            data = filtered;
+        }
+        info!(bytes = data.len(), "Captured updated session rollout");
+        let _ = tx.send(Ok(event_response(Event::UpdatedRollout(data)))).await;
+    }
+    if !status.success() && !aborted && started.elapsed() < STARTUP_FAILURE_WINDOW {
+        let tail: Vec<&str> = stderr.tail.iter().map(String::as_str).collect();
+        let kind = events::classify_failure(&tail);
+        // 已输出事件且无法归类的失败属于普通的任务失败，客户端已从事件流中看到原因
+        if kind != ErrorKind::Unknown || events_so_far == 0 {
+            let message = format!("codex exited with code {} shortly after start: {}", exit_code(&status), tail.last().copied().unwrap_or("no stderr output"));
+            warn!(session_id, kind = kind.as_str_name(), "{message}");
+            let _ = tx.send(Ok(event_response(Event::AgentError(AgentError { kind: kind as i32, message })))).await;
+        }
+    }
+    if !status.success()
+        && !aborted
+        && let Some(error) = resource_limits.exhausted(&status, cpu_used, stderr.tail.make_contiguous())
+    {
+        warn!(session_id, kind = error.kind().as_str_name(), "{}", error.message);
+        let _ = tx.send(Ok(event_response(Event::AgentError(error)))).await;
+    }
+    // 截断后客户端未收到结尾的 STDERR，失败时附上最近的若干行
+    if !status.success() && !aborted && stderr.truncated {
+        let tail: Vec<&str> = stderr.tail.iter().map(String::as_str).collect();
+        let message = format!("codex exited with code {}; last {} stderr lines:\n{}", exit_code(&status), tail.len(), tail.join("\n"));
+        let _ = tx.send(Ok(event_response(Event::Error(message)))).await;
+    }
+    if cancelled {
+        let _ = tx.send(Ok(event_response(Event::Cancelled(Cancelled { forced })))).await;
+    }
+    send_task_complete(&tx, &status, status.success() && !aborted, completion).await;
+    Ok(StreamExit::Finished(status.code()))
+}
+
+// 与 stdout/stderr 的读取并行写入 prompt：大 prompt 写满管道时，codex 可能正阻塞在写 stdout 上。
+// 写入完成 (或失败) 后关闭 stdin
+fn spawn_stdin_writer(mut stdin: tokio::process::ChildStdin, prompt: String, tx: EventSender) {
+    tokio::spawn(async move {
+        if let Err(e) = stdin.write_all(prompt.as_bytes()).await {
+            warn!(bytes = prompt.len(), "Failed to write prompt to codex stdin: {e}");
+            let _ = tx.send(Ok(event_response(Event::Error(format!("failed to write prompt to codex stdin: {e}"))))).await;
+        }
+    });
+}
+
+// 请求级的 codex 进程资源上限 (setrlimit)，None 表示不限制
+#[derive(Debug, Clone, Copy, Default)]
+struct ResourceLimits {
+    mem_limit_mb: Option<u64>,
+    cpu_time_sec: Option<u64>,
+}
+
+impl ResourceLimits {
+    fn from_request(req: &RunTaskRequest) -> Self {
+        Self {
+            mem_limit_mb: (req.mem_limit_mb > 0).then_some(req.mem_limit_mb),
+            cpu_time_sec: (req.cpu_time_sec > 0).then_some(u64::from(req.cpu_time_sec)),
+        }
+    }
+
+    // 在 fork 之后、exec 之前设置上限；RLIMIT_CPU 的软上限触发 SIGXCPU，硬上限多留 5s 后由内核 SIGKILL
+    #[cfg(unix)]
+    #[allow(clippy::unnecessary_cast)]
+    fn apply(self, cmd: &mut Command) {
+        if self.mem_limit_mb.is_none() && self.cpu_time_sec.is_none() { return; }
+        let address_space = self.mem_limit_mb.map(|mb| mb.saturating_mul(1024 * 1024) as libc::rlim_t);
+        let cpu = self.cpu_time_sec.map(|secs| secs as libc::rlim_t);
+        // SAFETY: 闭包只调用 async-signal-safe 的 setrlimit，不分配内存也不获取锁
+        unsafe {
+            cmd.pre_exec(move || {
+                if let Some(bytes) = address_space {
+                    let limit = libc::rlimit { rlim_cur: bytes, rlim_max: bytes };
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) != 0 { return Err(std::io::Error::last_os_error()); }
+                }
+                if let Some(secs) = cpu {
+                    let limit = libc::rlimit { rlim_cur: secs, rlim_max: secs.saturating_add(CPU_HARD_LIMIT_GRACE_SECS as libc::rlim_t) };
+                    if libc::setrlimit(libc::RLIMIT_CPU, &limit) != 0 { return Err(std::io::Error::last_os_error()); }
+                }
+                Ok(())
+            });
+        }
+    }
+
+    // codex 的退出是否由资源上限造成：CPU 超限表现为 SIGXCPU，或 codex 自身 CPU 时间 (cpu_used) 达到硬上限后的 SIGKILL；
+    // 地址空间耗尽时 Rust 程序报告分配失败后 abort。其余 SIGKILL (如 OOM killer) 只报告被终止
+    #[cfg(unix)]
+    fn exhausted(self, status: &std::process::ExitStatus, cpu_used: Option<Duration>, stderr_tail: &[String]) -> Option<AgentError> {
+        use std::os::unix::process::ExitStatusExt;
+        let signal = status.signal()?;
+        let error = |kind: ErrorKind, message: String| Some(AgentError { kind: kind as i32, message });
+        if let Some(secs) = self.cpu_time_sec
+            && (signal == libc::SIGXCPU
+                || (signal == libc::SIGKILL && cpu_used.is_some_and(|used| used >= Duration::from_secs(secs.saturating_add(CPU_HARD_LIMIT_GRACE_SECS)))))
+        {
+            return error(ErrorKind::CpuLimitExceeded, format!("codex exceeded its CPU time limit of {secs}s (cpu_time_sec) and was killed"));
+        }
+        let out_of_memory = stderr_tail.iter().any(|line| line.contains("memory allocation of") || line.contains("out of memory"));
+        if let Some(mb) = self.mem_limit_mb
+            && (signal == libc::SIGABRT || signal == libc::SIGKILL || signal == libc::SIGSEGV)
+            && out_of_memory
+        {
+            return error(ErrorKind::MemoryLimitExceeded, format!("codex exceeded its memory limit of {mb} MiB (mem_limit_mb) and was killed"));
+        }
+        if signal == libc::SIGKILL && (self.cpu_time_sec.is_some() || self.mem_limit_mb.is_some()) {
+            let used = cpu_used.map_or_else(|| "unknown".to_string(), |used| format!("{:.1}s", used.as_secs_f64()));
+            return error(ErrorKind::Unknown, format!("codex was killed by SIGKILL after {used} of CPU time; not attributable to its resource limits"));
+        }
+        None
+    }
+
+    #[cfg(not(unix))]
+    fn exhausted(self, _status: &std::process::ExitStatus, _cpu_used: Option<Duration>, _stderr_tail: &[String]) -> Option<AgentError> {
+        None
+    }
+}
+
+// RLIMIT_CPU 硬上限比软上限多留的秒数，供 codex 处理 SIGXCPU
+const CPU_HARD_LIMIT_GRACE_SECS: u64 = 5;
+
+// 等待 codex 退出但不回收 (waitid WNOWAIT)，返回其 CPU 时间 (user + sys，含 codex 已回收的子进程)；
+// 只统计这一个 pid，不受其他任务的 codex 影响。codex 已被回收或无法读取时为 None
+#[cfg(target_os = "linux")]
+async fn exited_child_cpu_time(child: &tokio::process::Child) -> Option<Duration> {
+    let pid = child.id()?;
+    tokio::task::spawn_blocking(move || {
+        // SAFETY: waitid 只写入传入的 siginfo 与 rusage；WNOWAIT 使 codex 保持可回收状态，pid 在 child.wait() 前不会被复用
+        let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            let rc = unsafe { libc::syscall(libc::SYS_waitid, libc::P_PID, pid as libc::id_t, &mut info as *mut libc::siginfo_t, libc::WEXITED | libc::WNOWAIT, &mut usage as *mut libc::rusage) };
+            if rc == 0 { break; }
+            if std::io::Error::last_os_error().kind() != std::io::ErrorKind::Interrupted { return None; }
+        }
+        let time = |t: libc::timeval| Duration::new(t.tv_sec as u64, t.tv_usec as u32 * 1000);
+        Some(time(usage.ru_utime) + time(usage.ru_stime))
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn exited_child_cpu_time(_child: &tokio::process::Child) -> Option<Duration> {
+    None
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct StderrLimits {
+    max_lines: Option<u64>,
+    max_bytes: Option<u64>,
+}
+
+// codex 的 STDERR 读取端；保留最近的若干行用于失败归类与截断后的错误报告
+struct StderrStream {
+    reader: tokio::io::Lines<BufReader<tokio::process::ChildStderr>>,
+    open: bool,
+    tail: std::collections::VecDeque<String>,
+    limits: StderrLimits,
+    forwarded_lines: u64,
+    forwarded_bytes: u64,
+    // 达到上限后不再转发，仍读取 (避免 codex 阻塞在写 STDERR) 并记入 tail
+    truncated: bool,
+}
+
+impl StderrStream {
+    fn new(stderr: tokio::process::ChildStderr, limits: StderrLimits) -> Self {
+        Self {
+            reader: BufReader::new(stderr).lines(),
+            open: true,
+            tail: Default::default(),
+            limits,
+            forwarded_lines: 0,
+            forwarded_bytes: 0,
+            truncated: false,
+        }
+    }
+
+    // 转发一行 STDERR；读到 EOF 或出错时标记为已关闭
+    async fn forward(&mut self, tx: &EventSender, session_log: Option<&SessionLog>, line: std::io::Result<Option<String>>) {
+        let Ok(Some(line)) = line else {
+            self.open = false;
+            return;
+        };
+        if let Some(log) = session_log { log.write("[STDERR]", &line); }
+        if !self.truncated {
+            let over_lines = self.limits.max_lines.is_some_and(|max| self.forwarded_lines >= max);
+            let over_bytes = self.limits.max_bytes.is_some_and(|max| self.forwarded_bytes + line.len() as u64 > max);
+            if over_lines || over_bytes {
+                self.truncated = true;
+                warn!(lines = self.forwarded_lines, bytes = self.forwarded_bytes, "codex stderr limit reached, no longer forwarding it");
+                let notice = format!("[STDERR] truncated after {} lines / {} bytes (MAX_STDERR_LINES / MAX_STDERR_BYTES); further stderr is not forwarded", self.forwarded_lines, self.forwarded_bytes);
+                let _ = tx.send(Ok(event_response(Event::AdapterLog(notice)))).await;
+            } else {
+                self.forwarded_lines += 1;
+                self.forwarded_bytes += line.len() as u64;
+                let _ = tx.send(Ok(event_response(Event::AdapterLog(format!("[STDERR] {line}"))))).await;
+            }
+        }
+        self.remember(line);
+    }
+
+    fn remember(&mut self, line: String) {
+        if self.tail.len() == STDERR_TAIL_LINES { self.tail.pop_front(); }
+        self.tail.push_back(line);
+    }
+
+    // STDOUT 结束后读完剩余的 STDERR；codex 派生的进程可能继续持有 STDERR，因此限时等待
+    async fn drain(&mut self, tx: &EventSender, session_log: Option<&SessionLog>) {
+        if !self.open { return; }
+        let drain = async {
+            while self.open {
+                let line = self.reader.next_line().await;
+                self.forward(tx, session_log, line).await;
+            }
+        };
+        if tokio::time::timeout(STDERR_DRAIN_TIMEOUT, drain).await.is_err() {
+            warn!("codex stderr still open {}s after stdout closed, no longer forwarding it", STDERR_DRAIN_TIMEOUT.as_secs());
+        }
+    }
+}
+
+// 第 attempt 次重试前的退避时长：base * 2^(attempt-1)，上限 60s
+fn retry_backoff(base_ms: u64, attempt: u32) -> Duration {
+    let factor = 1u64 << attempt.saturating_sub(1).min(16);
+    Duration::from_millis(base_ms.saturating_mul(factor)).min(Duration::from_secs(60))
+}
+
+// 任务结束事件；被信号终止时退出码按 shell 惯例记为 128 + 信号值
+async fn send_task_complete(tx: &EventSender, status: &std::process::ExitStatus, success: bool, completion: TaskCompletion<'_>) {
+    let exit_code = exit_code(status);
+    info!(exit_code, success, "codex exited");
+    telemetry::record_codex_exit(exit_code);
+    let _ = tx.send(Ok(event_response(Event::TaskComplete(task_complete(exit_code, success, completion))))).await;
+}
+
+// TaskComplete 中不随单次尝试变化的字段
+#[derive(Debug, Clone, Copy)]
+struct TaskCompletion<'a> {
+    task_started_at: &'a prost_types::Timestamp,
+    // 持久化工作区的保留时长；任务结束后自此刻起算
+    workspace_ttl: Option<Duration>,
+}
+
+fn task_complete(exit_code: i32, success: bool, completion: TaskCompletion<'_>) -> TaskComplete {
+    let ended = chrono::Utc::now();
+    TaskComplete {
+        exit_code,
+        success,
+        task_started_at: Some(completion.task_started_at.clone()),
+        task_ended_at: Some(to_timestamp(ended)),
+        // 元数据中的 last_task_time 精确到秒且在 TaskComplete 之后写入，按秒截断保证不晚于实际过期时刻
+        workspace_expires_at: completion.workspace_ttl.map(|ttl| prost_types::Timestamp { seconds: ended.timestamp().saturating_add(ttl.as_secs() as i64), nanos: 0 }),
+    }
+}
+
+fn exit_code(status: &std::process::ExitStatus) -> i32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+        if let Some(signal) = status.signal() { return 128 + signal; }
+    }
+    status.code().unwrap_or(-1)
+}
+
+// 等待 codex 退出直到 deadline，到期仍未退出时终止进程组；返回是否被强制终止
+async fn wait_or_kill(child: &mut tokio::process::Child, deadline: tokio::time::Instant) -> bool {
+    if tokio::time::timeout_at(deadline, child.wait()).await.is_ok() { return false; }
+    kill_tree(child).await;
+    true
+}
+
+async fn sleep_until_if_set(deadline: Option<tokio::time::Instant>) {
+    match deadline {
+        Some(deadline) => tokio::time::sleep_until(deadline).await,
+        None => std::future::pending().await,
+    }
+}
+
+async fn tick_if_enabled(interval: &mut Option<tokio::time::Interval>) {
+    match interval {
+        Some(interval) => { interval.tick().await; }
+        None => std::future::pending().await,
+    }
+}
+
+// 请求 codex 自行退出 (SIGTERM)，给它机会写完 rollout；信号发往整个进程组
+#[cfg(unix)]
+fn terminate(child: &mut tokio::process::Child) {
+    if let Some(pid) = child.id() {
+        // SAFETY: codex 以独立进程组启动 (pgid == pid)，仅向该进程组发送信号
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGTERM); }
+    }
+}
+
+// 终止 codex 及其派生的子进程 (如 MCP server)，并回收 codex 本身
+#[cfg(unix)]
+async fn kill_tree(child: &mut tokio::process::Child) {
+    if let Some(pid) = child.id() {
+        // SAFETY: 同上；须在 child.kill() 回收进程之前发送，避免 pid 被复用
+        unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL); }
+    }
+    let _ = child.kill().await;
+}
+
+// Windows 没有进程组信号，由 taskkill /T 沿进程树终止 codex 派生的子进程
+#[cfg(windows)]
+async fn kill_tree(child: &mut tokio::process::Child) {
+    if let Some(pid) = child.id() {
+        let status = Command::new("taskkill").arg("/T").arg("/F").arg("/PID").arg(pid.to_string())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        if let Err(e) = status { warn!(pid, "Failed to run taskkill: {e}"); }
+    }
+    let _ = child.kill().await;
+}
+
+// 不带 /F 的 taskkill 请求进程树自行退出；失败时直接结束 codex
+#[cfg(windows)]
+fn terminate(child: &mut tokio::process::Child) {
+    let Some(pid) = child.id() else { return };
+    let spawned = std::process::Command::new("taskkill").arg("/T").arg("/PID").arg(pid.to_string())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn();
+    if spawned.is_err() { let _ = child.start_kill(); }
+}
+
+#[cfg(not(any(unix, windows)))]
+async fn kill_tree(child: &mut tokio::process::Child) {
+    let _ = child.kill().await;
+}
+
+#[cfg(not(any(unix, windows)))]
+fn terminate(child: &mut tokio::process::Child) {
+    let _ = child.start_kill();
+}
+
+// codex 可执行文件的 (路径, SHA-256)，首次查询时计算并缓存；无法定位时为空字符串
+async fn codex_binary_fingerprint(codex_bin: &Path) -> &'static (String, String) {
+    static FINGERPRINT: tokio::sync::OnceCell<(String, String)> = tokio::sync::OnceCell::const_new();
+    FINGERPRINT
+        .get_or_init(|| async {
+            let codex_bin = codex_bin.to_path_buf();
+            let hashed = tokio::task::spawn_blocking(move || -> anyhow::Result<(String, String)> {
+                let path = which::which(&codex_bin)?;
+                let mut file = std::fs::File::open(&path)?;
+                let mut hasher = Sha256::new();
+                std::io::copy(&mut file, &mut hasher)?;
+                Ok((path.display().to_string(), format!("{:x}", hasher.finalize())))
+            })
+            .await;
+            match hashed.map_err(anyhow::Error::from).and_then(std::convert::identity) {
+                Ok(fingerprint) => fingerprint,
+                Err(e) => {
+                    warn!("Failed to hash codex binary: {e}");
+                    (String::new(), String::new())
+                }
+            }
+        })
+        .await
+}
+
+// 为事件打上生成时刻的时间戳，便于客户端构建时间线与测量首字延迟
+fn event_response(event: Event) -> RunTaskResponse {
+    RunTaskResponse {
+        event: Some(event),
+        event_timestamp: Some(now_timestamp()),
+        event_schema_version: ADAPTER_EVENT_SCHEMA_VERSION.to_string(),
+    }
+}
+
+fn now_timestamp() -> prost_types::Timestamp {
+    to_timestamp(chrono::Utc::now())
+}
+
+fn to_timestamp(time: chrono::DateTime<chrono::Utc>) -> prost_types::Timestamp {
+    prost_types::Timestamp { seconds: time.timestamp(), nanos: time.timestamp_subsec_nanos() as i32 }
+}
+
+// codex home 中找到的 rollout 及其内容
+struct ExtractedRollout {
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+// 优先返回属于 id 的 rollout：文件名为 rollout-*-{id}.jsonl，或首行 session_meta 的 id 与之相同；
+// 都没有时 (如 codex 为新会话分配了自己的 id) 退回到最新修改的 .jsonl
+async fn extract_updated_rollout(home: &Path, id: &str) -> anyhow::Result<Option<ExtractedRollout>> {
+    let root = home.join("sessions");
+    if !root.exists() { return Ok(None); }
+
+    let mut candidates = Vec::new();
+    for entry in walkdir::WalkDir::new(&root).follow_links(false) {
+        let entry = entry?;
+        if entry.file_type().is_file() && entry.path().extension().is_some_and(|ext| ext == "jsonl") {
+            let mtime = entry.metadata()?.modified()?;
+            candidates.push((mtime, entry.into_path()));
+        }
+    }
+    // 最新的在前，同一会话存在多个文件时取最新的
+    candidates.sort_by(|a, b| b.0.cmp(&a.0));
+
+    let by_name = candidates.iter().find(|(_, p)| p.file_name().is_some_and(|n| is_rollout_file_for(&n.to_string_lossy(), id)));
+    let matched = match by_name {
+        Some((_, p)) => Some(p.clone()),
+        None => candidates.iter().find(|(_, p)| rollout_session_id(p).as_deref() == Some(id)).map(|(_, p)| p.clone()),
+    };
+    let path = match matched {
+        Some(p) => p,
+        None => match candidates.into_iter().next() {
+            Some((_, p)) => {
+                info!(session_id = id, path = %p.display(), "No rollout matches session id, using newest");
+                p
+            }
+            None => return Ok(None),
+        },
+    };
+    info!(path = %path.display(), "Extracted rollout file");
+    let data = tokio::fs::read(&path).await?;
+    Ok(Some(ExtractedRollout { path, data }))
+}
+
+// 读取 rollout 首行 session_meta 中的会话 id
+fn rollout_session_id(path: &Path) -> Option<String> {
+    use std::io::BufRead;
+    let file = std::fs::File::open(path).ok()?;
+    let mut first = String::new();
+    std::io::BufReader::new(file).read_line(&mut first).ok()?;
+    rollout_first_line_session_id(first.as_bytes())
+}
+
+fn rollout_first_line_session_id(data: &[u8]) -> Option<String> {
+    let first = data.split(|b| *b == b'\n').next()?;
+    let line: serde_json::Value = serde_json::from_slice(first).ok()?;
+    let payload = &line["payload"];
+    payload["id"].as_str().or_else(|| payload["session_id"].as_str()).map(str::to_string)
+}
+
+// 按 system → instructions → developer → prompt 的顺序拼接，完全重复的段落只保留第一次出现
+fn build_full_prompt(prompt: &str, config: Option<&SessionConfig>) -> String {
+    let sections = config
+        .into_iter()
+        .flat_map(|c| [c.system_prompt.as_deref(), c.instructions.as_deref(), c.developer_instructions.as_deref()])
+        .flatten()
+        .chain(std::iter::once(prompt));
+    let mut kept: Vec<&str> = Vec::new();
+    for section in sections {
+        if !kept.contains(&section) { kept.push(section); }
+    }
+    let delimiter = config.and_then(|c| c.prompt_section_delimiter.as_deref()).unwrap_or("\n\n");
+    kept.join(delimiter)
+}
+
+const ANTHROPIC_DEFAULT_BASE_URL: &str = "https://api.anthropic.com";
+const ANTHROPIC_DEFAULT_ENV_KEY: &str = "ANTHROPIC_API_KEY";
+
+// 按 wire_api 补全 provider 的默认值 (需在 env_key 查找 bearer token 之前调用)
+fn apply_provider_defaults(provider: &mut ModelProviderInfo) {
+    if matches!(WireApi::try_from(provider.wire_api), Ok(WireApi::Anthropic)) {
+        if provider.base_url.as_deref().is_none_or(str::is_empty) {
+            provider.base_url = Some(ANTHROPIC_DEFAULT_BASE_URL.to_string());
+        }
+        if provider.env_key.as_deref().is_none_or(str::is_empty) {
+            provider.env_key = Some(ANTHROPIC_DEFAULT_ENV_KEY.to_string());
+        }
+    }
+}
+
+// 去掉末尾的 "/" (避免 codex 拼出 "//v1/..."), 缺少协议时补全为 https
+fn normalize_base_url(url: &str) -> String {
+    let trimmed = url.trim().trim_end_matches('/');
+    if trimmed.contains("://") {
+        trimmed.to_string()
+    } else {
+        format!("https://{trimmed}")
+    }
+}
+
+// codex 支持的 MCP 传输类型
+const KNOWN_MCP_SERVER_TYPES: &[&str] = &["stdio", "http", "sse"];
+
+fn validate_mcp_server_type(t: &str) -> bool {
+    KNOWN_MCP_SERVER_TYPES.contains(&t)
+}
+
+fn validate_mcp_servers(config: &SessionConfig) -> Result<(), AdapterError> {
+    for (name, def) in &config.mcp_servers {
+        if !validate_mcp_server_type(&def.server_type) {
+            return Err(AdapterError::InvalidArgument(format!(
+                "mcp server {name:?} has unknown type {:?} (expected one of {KNOWN_MCP_SERVER_TYPES:?})",
+                def.server_type
+            )));
+        }
+        match def.server_type.as_str() {
+            "stdio" if def.command.is_empty() => {
+                return Err(AdapterError::InvalidArgument(format!("stdio mcp server {name:?} requires a non-empty command")));
+            }
+            "http" | "sse" => {
+                let valid = url::Url::parse(&def.url).is_ok_and(|u| matches!(u.scheme(), "http" | "https"));
+                if !valid {
+                    return Err(AdapterError::InvalidArgument(format!(
+                        "{} mcp server {name:?} requires a valid http(s) url, got {:?}",
+                        def.server_type, def.url
+                    )));
+                }
+            }
+            _ => {}
+        }
+        for key in def.env.keys() {
+            validate_env_var_name(key).map_err(|reason| AdapterError::InvalidArgument(format!("mcp server {name:?} env var {key:?} {reason}")))?;
+        }
+        for (field, value) in [("startup_timeout_sec", def.startup_timeout_sec), ("tool_timeout_sec", def.tool_timeout_sec)] {
+            if let Some(secs) = value
+                && !(secs.is_finite() && secs > 0.0)
+            {
+                return Err(AdapterError::InvalidArgument(format!("mcp server {name:?} {field} must be a positive number of seconds, got {secs}")));
+            }
+        }
+    }
+    Ok(())
+}
+
+// 禁止客户端注入的环境变量 (可劫持动态链接或解释器加载路径)
+const ENV_VAR_DENYLIST: &[&str] = &[
+    "LD_PRELOAD",
+    "LD_LIBRARY_PATH",
+    "LD_AUDIT",
+    "DYLD_INSERT_LIBRARIES",
+    "DYLD_LIBRARY_PATH",
+    "PYTHONSTARTUP",
+    "NODE_OPTIONS",
+    "BASH_ENV",
+];
+
+fn validate_provider_timeouts(provider: &ModelProviderInfo) -> Result<(), AdapterError> {
+    if provider.connect_timeout_ms == Some(0) || provider.read_timeout_ms == Some(0) {
+        return Err(AdapterError::InvalidArgument(format!("provider {:?}: timeouts must be non-zero when set", provider.name)));
+    }
+    if let (Some(connect), Some(read)) = (provider.connect_timeout_ms, provider.read_timeout_ms)
+        && read < connect
+    {
+        return Err(AdapterError::InvalidArgument(format!(
+            "provider {:?}: read_timeout_ms ({read}) must be >= connect_timeout_ms ({connect})",
+            provider.name
+        )));
+    }
+    Ok(())
+}
+
+fn validate_env_var_name(key: &str) -> Result<(), &'static str> {
+    let mut chars = key.chars();
+    let valid = key.len() <= 128
+        && chars.next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err("is not a valid environment variable name");
+    }
+    if ENV_VAR_DENYLIST.iter().any(|d| d.eq_ignore_ascii_case(key)) {
+        return Err("is not allowed");
+    }
+    Ok(())
+}
+
+fn generate_config_toml(config: &SessionConfig) -> Result<String, AdapterError> {
+    validate_model_params(config)?;
+    validate_mcp_servers(config)?;
+    if let Some(provider) = &config.provider_info { validate_provider_timeouts(provider)?; }
+    config_toml::render(config)
+}
+
+const REASONING_EFFORTS: &[&str] = &["minimal", "low", "medium", "high"];
+const VERBOSITY_LEVELS: &[&str] = &["low", "medium", "high"];
+
+fn validate_model_params(config: &SessionConfig) -> Result<(), AdapterError> {
+    let checks = [
+        ("model_reasoning_effort", &config.model_reasoning_effort, REASONING_EFFORTS),
+        ("model_verbosity", &config.model_verbosity, VERBOSITY_LEVELS),
+    ];
+    for (field, value, allowed) in checks {
+        if !value.is_empty() && !allowed.contains(&value.as_str()) {
+            return Err(AdapterError::InvalidArgument(format!("unknown {field} {value:?} (expected one of {allowed:?})")));
+        }
+    }
+    Ok(())
+}
+
+// 以 env_key 代替 bearer token 生成 config.toml，用于预览而不泄露密钥
+fn redacted_config_toml(config: Option<&SessionConfig>) -> Result<String, AdapterError> {
+    let mut config = config.cloned().unwrap_or_default();
+    if let Some(provider) = &mut config.provider_info {
+        apply_provider_defaults(provider);
+        provider.experimental_bearer_token = None;
+    }
+    generate_config_toml(&config)
+}
+
+// dry_run：按实际运行的方式解析路径并构建命令，但不创建目录、不写文件、不启动 codex
+fn build_dry_run(req: &RunTaskRequest, config: &ServerConfig, trace: &TraceContext) -> Result<DryRun, AdapterError> {
+    let config_toml = match &req.session_config {
+        Some(session_config) => redacted_config_toml(Some(session_config))?,
+        None => String::new(),
+    };
+    let TaskPaths { codex_home, work_dir, strace_log, .. } = resolve_task_paths(req, config, &config.scratch_root.join("<scratch>"))?;
+    let resume_id = (!req.history_rollout.is_empty()).then_some(req.session_id.as_str());
+    let cmd = build_codex_command(req, config, &codex_home, &work_dir, trace, strace_log.as_deref(), resume_id);
+    let argv = std::iter::once(cmd.as_std().get_program()).chain(cmd.as_std().get_args()).map(|a| a.to_string_lossy().into_owned()).collect();
+    let context_files = req.context_files.iter().map(|f| normalize_context_file_path(&f.path)).filter(|p| !p.is_empty() && is_safe_context_path(p)).collect();
+    Ok(DryRun {
+        config_toml,
+        argv,
+        work_dir: work_dir.display().to_string(),
+        codex_home: codex_home.display().to_string(),
+        context_files,
+    })
+}
+
+// dry_run_toml 的预览：以 env_key 代替 bearer token，并用 toml 解析器校验生成结果
+fn preview_config_toml(config: Option<&SessionConfig>) -> String {
+    let (toml, errors) = match redacted_config_toml(config) {
+        Ok(toml) => {
+            let errors = toml::from_str::<toml::Value>(&toml).err().map(|e| e.to_string());
+            (toml, errors)
+        }
+        Err(e) => (String::new(), Some(e.to_string())),
+    };
+    let validation = errors.map_or_else(|| "ok".to_string(), |e| format!("error: {e}"));
+    format!("[DRY RUN] config.toml (validation: {validation})\n{toml}")
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    tracing_subscriber::fmt().with_env_filter(LOG_LEVEL).init();
+    let config = ServerConfig::from_env();
+    let addr: std::net::SocketAddr = config
+        .listen_addr
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid AGENT_SERVER_ADDR {:?}: {e} (expected host:port, e.g. 127.0.0.1:50051)", config.listen_addr))?;
+    // 超出上限的请求在解码成结构体之前即被拒绝，避免单个巨型请求导致 OOM
+    let max_request_bytes = config.max_request_bytes;
+    regex::Regex::new(&config.transient_error_pattern).map_err(|e| format!("invalid TRANSIENT_ERROR_PATTERN: {e}"))?;
+    telemetry::install_exporter(&config.metrics_addr)?;
+    workspace_gc::spawn_gc_worker(Duration::from_secs(config.workspace_gc_interval_secs.max(1)), config.persistent_workspace_root.clone());
+    let codex_usable = probe_codex_version(&config.codex_bin).await?;
+    // grpc.health.v1：codex 不可用或服务正在关闭时 AgentService 报告 NOT_SERVING
+    let (mut health, health_service) = tonic_health::server::health_reporter();
+    if codex_usable {
+        health.set_serving::<AgentServiceServer<MyAgentService>>().await;
+    } else {
+        health.set_not_serving::<AgentServiceServer<MyAgentService>>().await;
+    }
+    let grace = Duration::from_secs(config.shutdown_grace_secs);
+    if config.server_token.is_none() {
+        warn!("AGENT_SERVER_TOKEN is not set: the gRPC service accepts unauthenticated requests");
+    }
+    info!(passthrough = ?config.env_passthrough, "codex runs with a cleared environment: only CODEX_ENV_PASSTHROUGH host variables and request env_vars are passed");
+    let tls = load_tls_config(config.tls_cert_path.as_deref(), config.tls_key_path.as_deref())?;
+    let interceptor = auth::bearer_interceptor(config.server_token.clone());
+    let adapter = MyAgentService::new(config);
+    let (registry, shutdown) = (adapter.registry.clone(), adapter.shutdown.clone());
+    let service = tonic::service::interceptor::InterceptedService::new(
+        AgentServiceServer::new(adapter).max_decoding_message_size(max_request_bytes),
+        interceptor.clone(),
+    );
+    // 先绑定再记录日志，端口为 0 时日志中是实际分配的端口
+    let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| format!("failed to bind {addr}: {e}"))?;
+    info!(tls = tls.is_some(), "Codex Agent Service listening on {}", listener.local_addr()?);
+    let mut builder = Server::builder();
+    if let Some(tls) = tls {
+        builder = builder.tls_config(tls)?;
+    }
+    // 健康检查供 Kubernetes 探针使用，不要求 bearer token
+    let router = builder.add_service(health_service).add_service(service);
+    // 供 grpcurl 等动态客户端发现接口定义；与业务接口使用同一 bearer token
+    #[cfg(feature = "reflection")]
+    let router = router.add_service(tonic::service::interceptor::InterceptedService::new(
+        tonic_reflection::server::Builder::configure()
+            .register_encoded_file_descriptor_set(agent::FILE_DESCRIPTOR_SET)
+            .build()?,
+        interceptor,
+    ));
+    router
+        .serve_with_incoming_shutdown(tokio_stream::wrappers::TcpListenerStream::new(listener), async move {
+            wait_for_shutdown_signal().await;
+            health.set_not_serving::<AgentServiceServer<MyAgentService>>().await;
+            info!(active_tasks = registry.running_count(), grace_secs = grace.as_secs(), "Shutdown signal received, no longer accepting connections");
+            tokio::spawn(drain_tasks(registry, shutdown, grace));
+        })
+        .await?;
+    info!("Codex Agent Service stopped");
+    Ok(())
+}
+
+// AGENT_SERVER_TLS_CERT / AGENT_SERVER_TLS_KEY 须成对设置；均未设置时返回 None (明文)
+fn load_tls_config(cert: Option<&Path>, key: Option<&Path>) -> Result<Option<ServerTlsConfig>, String> {
+    let (cert, key) = match (cert, key) {
+        (None, None) => return Ok(None),
+        (Some(cert), Some(key)) => (cert, key),
+        _ => return Err("AGENT_SERVER_TLS_CERT and AGENT_SERVER_TLS_KEY must be set together".to_string()),
+    };
+    let read = |path: &Path| std::fs::read(path).map_err(|e| format!("failed to read {}: {e}", path.display()));
+    let identity = Identity::from_pem(read(cert)?, read(key)?);
+    Ok(Some(ServerTlsConfig::new().identity(identity)))
+}
+
+// 启动时确认 codex 可执行，配置错误在启动阶段即暴露而非等到第一个请求。
+// 无法启动时返回错误；能启动但 --version 失败时返回 false (健康检查报告 NOT_SERVING)
+async fn probe_codex_version(codex_bin: &Path) -> Result<bool, String> {
+    let output = Command::new(codex_bin)
+        .arg("--version")
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .map_err(|e| format!("failed to run {} --version (set CODEX_BIN to the codex executable): {e}", codex_bin.display()))?;
+    let version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if output.status.success() {
+        info!(codex_bin = %codex_bin.display(), version = %version, "Detected codex binary");
+    } else {
+        warn!(codex_bin = %codex_bin.display(), status = %output.status, "codex --version exited unsuccessfully");
+    }
+    Ok(output.status.success())
+}
+
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = sigterm.recv() => {}
+                    _ = tokio::signal::ctrl_c() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Failed to install SIGTERM handler: {e}"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// 宽限期内等待活跃任务自然结束 (以便回传 UpdatedRollout)，之后终止剩余的 codex 进程
+async fn drain_tasks(registry: Arc<TaskRegistry>, shutdown: CancellationToken, grace: Duration) {
+    let deadline = tokio::time::Instant::now() + grace;
+    while registry.running_count() > 0 && tokio::time::Instant::now() < deadline {
+        tokio::time::sleep(Duration::from_millis(200)).await;
+    }
+    let remaining = registry.running_count();
+    if remaining > 0 {
+        warn!(remaining_tasks = remaining, "Shutdown grace period elapsed, killing remaining codex processes");
+    }
+    shutdown.cancel();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn normalize_base_url_strips_trailing_slashes_and_adds_scheme() {
+        assert_eq!(normalize_base_url("https://api.example.com/"), "https://api.example.com");
+        assert_eq!(normalize_base_url("https://api.example.com/v1///"), "https://api.example.com/v1");
+        assert_eq!(normalize_base_url("api.example.com/v1"), "https://api.example.com/v1");
+        assert_eq!(normalize_base_url("http://localhost:8080"), "http://localhost:8080");
+    }
+
+    #[test]
+    fn normalize_context_file_path_converts_backslashes() {
+        assert_eq!(normalize_context_file_path("src\\lib\\mod.rs"), "src/lib/mod.rs");
+        assert_eq!(normalize_context_file_path("  src\\main.rs\n"), "src/main.rs");
+    }
+
+    #[test]
+    fn normalize_context_file_path_handles_mixed_and_repeated_separators() {
+        assert_eq!(normalize_context_file_path("src\\lib//nested\\\\mod.rs"), "src/lib/nested/mod.rs");
+        assert_eq!(normalize_context_file_path("\\\\etc/passwd"), "/etc/passwd");
+        assert!(!is_safe_context_path(&normalize_context_file_path("src\\..\\..\\secret")));
+    }
+
+    #[tokio::test]
+    async fn extract_updated_rollout_returns_the_requested_session() {
+        let home = TempDir::new().unwrap();
+        let day = home.path().join("sessions/2025/01/02");
+        std::fs::create_dir_all(&day).unwrap();
+        let meta = |id: &str| format!("{{\"timestamp\":\"t\",\"type\":\"session_meta\",\"payload\":{{\"id\":\"{id}\"}}}}\n");
+        std::fs::write(day.join("rollout-2025-01-02T00-00-00-aaaa.jsonl"), meta("aaaa")).unwrap();
+        std::fs::write(day.join("rollout-bbbb.jsonl"), meta("bbbb")).unwrap();
+        std::fs::write(day.join("rollout-2025-01-02T00-00-01-cccc.jsonl"), meta("cccc")).unwrap();
+
+        let by_name = extract_updated_rollout(home.path(), "bbbb").await.unwrap().unwrap();
+        assert_eq!(String::from_utf8(by_name.data).unwrap(), meta("bbbb"));
+        assert_eq!(by_name.path, day.join("rollout-bbbb.jsonl"));
+
+        // id 只是文件名后缀的一部分时不按文件名匹配
+        assert!(!is_rollout_file_for("rollout-bbbb.jsonl", "bb"));
+        assert!(find_rollout_file(&home.path().join("sessions"), "bb").unwrap().is_none());
+
+        std::fs::rename(day.join("rollout-2025-01-02T00-00-00-aaaa.jsonl"), day.join("rollout-renamed.jsonl")).unwrap();
+        let by_meta = extract_updated_rollout(home.path(), "aaaa").await.unwrap().unwrap();
+        assert_eq!(String::from_utf8(by_meta.data).unwrap(), meta("aaaa"));
+    }
+
+    #[test]
+    fn safe_join_rejects_parent_and_absolute_paths() {
+        let base = TempDir::new().unwrap();
+        let canonical = std::fs::canonicalize(base.path()).unwrap();
+        assert_eq!(safe_join(base.path(), "src/lib.rs"), Some(canonical.join("src/lib.rs")));
+        assert_eq!(safe_join(base.path(), "../outside.txt"), None);
+        assert_eq!(safe_join(base.path(), "src/../../outside.txt"), None);
+        assert_eq!(safe_join(base.path(), "/etc/passwd"), None);
+        assert_eq!(safe_join(base.path(), ""), None);
+        // `..` 只在作为路径组件时才是上级目录
+        assert_eq!(safe_join(base.path(), "notes..txt"), Some(canonical.join("notes..txt")));
+    }
+
+    #[cfg(windows)]
+    #[test]
+    fn safe_join_rejects_windows_absolute_paths() {
+        let base = TempDir::new().unwrap();
+        for path in [r"C:\Windows\win.ini", "C:relative.txt", r"\\server\share\file", r"\rooted.txt"] {
+            assert_eq!(safe_join(base.path(), path), None, "{path}");
+        }
+        assert!(safe_join(base.path(), r"src\lib.rs").is_some());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn safe_join_rejects_symlink_escapes() {
+        let base = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        std::os::unix::fs::symlink(outside.path(), base.path().join("link")).unwrap();
+        std::os::unix::fs::symlink(outside.path().join("missing.txt"), base.path().join("dangling")).unwrap();
+        std::fs::create_dir(base.path().join("inner")).unwrap();
+        std::os::unix::fs::symlink(base.path().join("inner"), base.path().join("inner-link")).unwrap();
+
+        assert_eq!(safe_join(base.path(), "link/escaped.txt"), None);
+        assert_eq!(safe_join(base.path(), "dangling"), None);
+        assert!(safe_join(base.path(), "inner-link/ok.txt").is_some());
+    }
+
+    #[test]
+    fn auto_compact_limit_and_history_persistence_are_configurable() {
+        let render = |config: SessionConfig| toml::from_str::<toml::Value>(&generate_config_toml(&config).unwrap()).unwrap();
+
+        let defaults = render(SessionConfig::default());
+        assert_eq!(defaults["model_auto_compact_token_limit"].as_integer(), Some(100_000));
+        assert_eq!(defaults["history"]["persistence"].as_str(), Some("save-all"));
+
+        let custom = render(SessionConfig { auto_compact_token_limit: Some(0), history_persistence: HistoryPersistence::None as i32, ..Default::default() });
+        assert!(custom.get("model_auto_compact_token_limit").is_none());
+        assert_eq!(custom["history"]["persistence"].as_str(), Some("none"));
+
+        let lower = render(SessionConfig { auto_compact_token_limit: Some(50_000), ..Default::default() });
+        assert_eq!(lower["model_auto_compact_token_limit"].as_integer(), Some(50_000));
+    }
+
+    #[test]
+    fn anthropic_provider_gets_default_base_url_and_env_key() {
+        let mut provider = ModelProviderInfo {
+            name: "anthropic".to_string(),
+            wire_api: WireApi::Anthropic as i32,
+            ..Default::default()
+        };
+        apply_provider_defaults(&mut provider);
+        let config = SessionConfig { provider_info: Some(provider), ..Default::default() };
+
+        let toml = generate_config_toml(&config).unwrap();
+
+        let expected: toml::Value = toml::from_str(
+            r#"
+name = "anthropic"
+base_url = "https://api.anthropic.com"
+wire_api = "anthropic"
+env_key = "ANTHROPIC_API_KEY"
+requires_openai_auth = false
+"#,
+        )
+        .unwrap();
+        let parsed: toml::Value = toml::from_str(&toml).unwrap();
+        assert_eq!(parsed["model_providers"]["anthropic"], expected);
+    }
+
+    #[test]
+    fn generated_config_toml_round_trips_special_characters() {
+        let instructions = "Line one\nSay \"hi\" and use C:\\path\\to\\file\n\tTabbed line";
+        let config = SessionConfig {
+            model: "gpt-5".to_string(),
+            instructions: Some(instructions.to_string()),
+            provider_info: Some(ModelProviderInfo {
+                name: "my provider.v2".to_string(),
+                base_url: Some("https://llm.example.com/v1/".to_string()),
+                env_key: Some("MY_KEY".to_string()),
+                http_headers: [("anthropic-version".to_string(), "2023-06-01 \"beta\"".to_string())].into(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+
+        let toml = generate_config_toml(&config).unwrap();
+        let parsed: toml::Value = toml::from_str(&toml).unwrap();
+
+        assert_eq!(parsed["model"].as_str(), Some("gpt-5"));
+        assert_eq!(parsed["instructions"].as_str(), Some(instructions));
+        assert_eq!(parsed["history"]["persistence"].as_str(), Some("save-all"));
+        let provider = &parsed["model_providers"]["my provider.v2"];
+        assert_eq!(provider["name"].as_str(), Some("my provider.v2"));
+        assert_eq!(provider["base_url"].as_str(), Some("https://llm.example.com/v1"));
+        assert_eq!(provider["env_key"].as_str(), Some("MY_KEY"));
+        assert_eq!(provider["http_headers"]["anthropic-version"].as_str(), Some("2023-06-01 \"beta\""));
+        assert!(provider.get("query_params").is_none());
+    }
+
+    #[test]
+    fn config_cache_generates_once_for_identical_sessions() {
+        let servers = (0..20).map(|i| {
+            let def = agent::McpServerDef { server_type: "stdio".to_string(), command: format!("mcp-{i}"), args: vec!["--stdio".to_string()], ..Default::default() };
+            (format!("server{i}"), def)
+        });
+        let config = SessionConfig { model: "gpt-5".to_string(), mcp_servers: servers.collect(), ..Default::default() };
+        let cache = config_cache::ConfigCache::new(std::num::NonZeroUsize::new(8).unwrap());
+
+        // 100 个相同配置的会话只生成一次
+        let generated = std::sync::atomic::AtomicUsize::new(0);
+        let counting = |c: &SessionConfig| {
+            generated.fetch_add(1, Ordering::SeqCst);
+            generate_config_toml(c)
+        };
+        let outputs: Vec<String> = (0..100).map(|_| cache.get_or_generate(&config, counting).unwrap()).collect();
+        assert_eq!(generated.load(Ordering::SeqCst), 1);
+        assert!(outputs.iter().all(|t| *t == generate_config_toml(&config).unwrap()));
+    }
+
+    #[test]
+    fn mcp_server_args_env_and_timeouts_are_emitted_only_when_set() {
+        let stdio = agent::McpServerDef {
+            server_type: "stdio".to_string(),
+            command: "uvx".to_string(),
+            args: vec!["mcp-server-fetch".to_string()],
+            env: [("API_KEY".to_string(), "secret".to_string())].into(),
+            startup_timeout_sec: Some(20.0),
+            ..Default::default()
+        };
+        let bare = agent::McpServerDef { server_type: "stdio".to_string(), command: "mcp".to_string(), ..Default::default() };
+        let config = SessionConfig {
+            mcp_servers: [("fetch".to_string(), stdio), ("bare".to_string(), bare)].into(),
+            ..Default::default()
+        };
+
+        let parsed: toml::Value = toml::from_str(&generate_config_toml(&config).unwrap()).unwrap();
+
+        let fetch = &parsed["mcp_servers"]["fetch"];
+        assert_eq!(fetch["args"].as_array().map(Vec::len), Some(1));
+        assert_eq!(fetch["env"]["API_KEY"].as_str(), Some("secret"));
+        assert_eq!(fetch["startup_timeout_sec"].as_float(), Some(20.0));
+        assert!(fetch.get("tool_timeout_sec").is_none());
+        let bare = parsed["mcp_servers"]["bare"].as_table().unwrap();
+        let mut keys: Vec<_> = bare.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["command", "type"]);
+    }
+
+    #[test]
+    fn validate_request_rejects_denylisted_env_vars() {
+        for key in ["LD_PRELOAD", "bash_env", "1BAD", "A-B"] {
+            let req = RunTaskRequest { session_id: "s1".to_string(), prompt: "hi".to_string(), env_vars: [(key.to_string(), "x".to_string())].into(), ..Default::default() };
+            assert!(matches!(validate_request(&req), Err(AdapterError::InvalidArgument(msg)) if msg.contains(key)), "{key}");
+        }
+        let req = RunTaskRequest { session_id: "s1".to_string(), prompt: "hi".to_string(), env_vars: [("OPENAI_API_KEY".to_string(), "sk".to_string())].into(), ..Default::default() };
+        assert!(validate_request(&req).is_ok());
+    }
+
+    #[test]
+    fn validate_request_requires_prompt_for_new_sessions() {
+        let fresh = RunTaskRequest { session_id: "s1".to_string(), ..Default::default() };
+        assert!(matches!(validate_request(&fresh), Err(AdapterError::InvalidArgument(_))));
+        let blank = RunTaskRequest { prompt: "  \n".to_string(), ..fresh.clone() };
+        assert!(validate_request(&blank).is_err());
+
+        let resumed = RunTaskRequest { history_rollout: b"{}".to_vec(), ..fresh.clone() };
+        assert!(validate_request(&resumed).is_ok());
+        let stdin_null = RunTaskRequest { stdin_null_mode: true, ..fresh.clone() };
+        assert!(validate_request(&stdin_null).is_ok());
+        let with_prompt = RunTaskRequest { prompt: "fix the build".to_string(), ..fresh };
+        assert!(validate_request(&with_prompt).is_ok());
+    }
+
+    #[test]
+    fn validate_request_requires_absolute_existing_base_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let req = |base_dir: String| RunTaskRequest { prompt: "hi".to_string(), base_dir, ..Default::default() };
+
+        assert!(validate_request(&req(dir.path().display().to_string())).is_ok());
+        assert!(validate_request(&req("relative/dir".to_string())).is_err());
+        assert!(validate_request(&req(dir.path().join("missing").display().to_string())).is_err());
+        let file = dir.path().join("file.txt");
+        std::fs::write(&file, "x").unwrap();
+        assert!(validate_request(&req(file.display().to_string())).is_err());
+    }
+
+    #[tokio::test]
+    async fn context_checksums_are_verified_against_the_written_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = File { path: "a.txt".to_string(), content: b"hello".to_vec(), checksum: format!("{:x}", Sha256::digest(b"hello")), mode: None };
+        std::fs::write(dir.path().join("a.txt"), "hello").unwrap();
+        assert!(verify_context_checksums(std::slice::from_ref(&file), dir.path()).await.is_ok());
+
+        // 请求内容正确但落盘内容不同，仍须报告不一致
+        std::fs::write(dir.path().join("a.txt"), "hellO").unwrap();
+        let err = verify_context_checksums(std::slice::from_ref(&file), dir.path()).await.unwrap_err();
+        assert!(matches!(err, AdapterError::DataLoss(msg) if msg.contains("a.txt")));
+    }
+
+    #[tokio::test]
+    async fn diff_injection_restores_files_changed_on_disk() {
+        let dir = tempfile::tempdir().unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(10);
+        let files = [
+            File { path: "a.txt".to_string(), content: b"a".to_vec(), ..Default::default() },
+            File { path: "b.txt".to_string(), content: b"b".to_vec(), ..Default::default() },
+        ];
+        let stats = inject_context_files_diff(&files, dir.path(), &tx).await.unwrap();
+        assert_eq!((stats.files_written, stats.files_skipped), (2, 0));
+
+        // codex 改写了 a.txt：清单中的哈希仍相同，但必须恢复客户端的内容
+        std::fs::write(dir.path().join("a.txt"), "edited by codex").unwrap();
+        let stats = inject_context_files_diff(&files, dir.path(), &tx).await.unwrap();
+        assert_eq!((stats.files_written, stats.files_skipped), (1, 1));
+        assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), b"a");
+
+        // 清单损坏时不再删除旧文件，但未变的文件仍按磁盘内容跳过
+        std::fs::write(dir.path().join(FILE_MANIFEST_NAME), "{not json").unwrap();
+        let stats = inject_context_files_diff(&files[..1], dir.path(), &tx).await.unwrap();
+        assert_eq!((stats.files_written, stats.files_skipped, stats.files_deleted), (0, 1, 0));
+        assert!(dir.path().join("b.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn config_profile_fills_fields_missing_from_the_inline_config() {
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("deep.toml"),
+            "model = \"o3\"\nmodel_reasoning_effort = \"high\"\nmodel_verbosity = \"low\"\nnetwork_access = true\nwritable_roots = [\"/data\"]\n\
+             auto_compact_token_limit = 50000\nhistory_persistence = \"none\"\napproval_policy = \"on-failure\"\n\
+             system_prompt = \"be terse\"\nprompt_section_delimiter = \"---\"\n",
+        ).unwrap();
+        let inline = SessionConfig { model_verbosity: "medium".to_string(), ..Default::default() };
+
+        let merged = profile::apply_profile(Some(dir.path()), "deep", Some(inline)).await.unwrap();
+        assert_eq!((merged.model.as_str(), merged.model_reasoning_effort.as_str(), merged.model_verbosity.as_str()), ("o3", "high", "medium"));
+        assert!(merged.network_access);
+        assert_eq!(merged.writable_roots, ["/data"]);
+        assert_eq!((merged.auto_compact_token_limit, merged.history_persistence()), (Some(50_000), HistoryPersistence::None));
+        assert_eq!(merged.approval_policy(), agent::ApprovalPolicy::OnFailure);
+        assert_eq!((merged.system_prompt.as_deref(), merged.prompt_section_delimiter.as_deref()), (Some("be terse"), Some("---")));
+    }
+
+    #[test]
+    fn persistent_workspaces_must_live_under_the_server_root() {
+        let root = tempfile::tempdir().unwrap();
+        let outside = tempfile::tempdir().unwrap();
+        let ws = root.path().join("ws1");
+        std::fs::create_dir(&ws).unwrap();
+
+        assert!(workspace_gc::persistent_workspace_path(None, &ws).is_err());
+        assert!(workspace_gc::persistent_workspace_path(Some(root.path()), root.path()).is_err());
+        assert!(workspace_gc::persistent_workspace_path(Some(root.path()), outside.path()).is_err());
+        assert!(workspace_gc::persistent_workspace_path(Some(root.path()), &ws.join("..").join("..")).is_err());
+        let canonical = workspace_gc::persistent_workspace_path(Some(root.path()), &ws).unwrap();
+        assert_eq!(canonical, std::fs::canonicalize(&ws).unwrap());
+
+        // 重启后从元数据文件重建登记表
+        std::fs::write(ws.join(workspace_gc::METADATA_FILE_NAME), "{}").unwrap();
+        std::fs::create_dir_all(ws.join("nested")).unwrap();
+        std::fs::create_dir(root.path().join("plain")).unwrap();
+        assert_eq!(workspace_gc::scan_persistent_workspaces(root.path()), vec![ws]);
+    }
+
+    // 测试用的 StreamOptions：不限制、不解析事件，测试只覆盖关心的字段
+    impl<'a> StreamOptions<'a> {
+        fn for_test(redactor: &'a RolloutRedactor, shutdown: &'a CancellationToken, cancel: &'a CancellationToken) -> Self {
+            StreamOptions {
+                session_id: "s1",
+                completion: TaskCompletion { task_started_at: &prost_types::Timestamp { seconds: 0, nanos: 0 }, workspace_ttl: None },
+                first_event_timeout: Duration::from_secs(10),
+                parse_events: false,
+                typed_events: false,
+                redactor,
+                stdout_limit_bytes: None,
+                budget_tokens: None,
+                warn_after: None,
+                task_timeout: None,
+                shutdown,
+                cancel,
+                cancel_grace: Duration::from_secs(10),
+                session_log: None,
+                heartbeat_interval: None,
+                event_filter: &[],
+                transient_error: None,
+                rollout_stream: None,
+                stderr_limits: StderrLimits::default(),
+                resource_limits: ResourceLimits::default(),
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn process_streams_preserves_stdout_stderr_interleaving() {
+        let script = r#"echo '{"type":"one"}'; sleep 0.2; echo warn-a >&2; sleep 0.2; echo '{"type":"two"}'; sleep 0.2; echo warn-b >&2"#;
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        let options = StreamOptions::for_test(&redactor, &shutdown, &cancel);
+
+        let exit_code = process_streams(child, tx, capture, home.path(), &options).await.unwrap();
+
+        assert_eq!(exit_code, StreamExit::Finished(Some(0)));
+        let mut order = Vec::new();
+        while let Some(Ok(response)) = rx.recv().await {
+            match response.event {
+                Some(Event::CodexEventJson(line)) => order.push(line),
+                Some(Event::AdapterLog(line)) => order.push(line),
+                Some(Event::TaskComplete(_)) => order.push("task_complete".to_string()),
+                _ => {}
+            }
+        }
+        assert_eq!(order, [r#"{"type":"one"}"#, "[STDERR] warn-a", r#"{"type":"two"}"#, "[STDERR] warn-b", "task_complete"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn quick_failures_report_agent_errors_only_when_classified_or_silent() {
+        let agent_errors = |script: &'static str| async move {
+            let child = Command::new("sh").arg("-c").arg(script).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn().unwrap();
+            let home = tempfile::tempdir().unwrap();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+            let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+            let redactor = RolloutRedactor::compile(&[]).unwrap();
+            let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+            let options = StreamOptions::for_test(&redactor, &shutdown, &cancel);
+            process_streams(child, tx, capture, home.path(), &options).await.unwrap();
+            let mut kinds = Vec::new();
+            while let Some(Ok(response)) = rx.recv().await {
+                if let Some(Event::AgentError(error)) = response.event { kinds.push(error.kind()); }
+            }
+            kinds
+        };
+
+        // 已输出事件的普通失败不视为启动失败
+        assert!(agent_errors(r#"echo '{"type":"turn.failed"}'; echo 'tests failed' >&2; exit 1"#).await.is_empty());
+        assert_eq!(agent_errors(r#"echo '{"type":"thread.started"}'; echo '401 Unauthorized' >&2; exit 1"#).await, [ErrorKind::AuthError]);
+        assert_eq!(agent_errors("echo 'boom' >&2; exit 1").await, [ErrorKind::Unknown]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn large_prompt_does_not_deadlock_against_chatty_child() {
+        // 先写出超过管道缓冲区的 stdout 再读取 stdin，顺序写入 prompt 时两端会互相阻塞
+        let script = r#"i=0; while [ $i -lt 10000 ]; do echo '{"type":"tick"}'; i=$((i+1)); done; wc -c | tr -d ' '"#;
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(script)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let prompt = "x".repeat(2 * 1024 * 1024);
+        spawn_stdin_writer(child.stdin.take().unwrap(), prompt, tx.clone());
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        let options = StreamOptions::for_test(&redactor, &shutdown, &cancel);
+        let collector = tokio::spawn(async move {
+            let mut last_line = None;
+            while let Some(Ok(response)) = rx.recv().await {
+                if let Some(Event::CodexEventJson(line)) = response.event { last_line = Some(line); }
+            }
+            last_line
+        });
+
+        let run = process_streams(child, tx, capture, home.path(), &options);
+        let exit_code = tokio::time::timeout(Duration::from_secs(30), run).await.expect("prompt write deadlocked").unwrap();
+
+        assert_eq!(exit_code, StreamExit::Finished(Some(0)));
+        assert_eq!(collector.await.unwrap().as_deref(), Some("2097152"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn transient_failures_are_handed_back_for_retry() {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"echo '{"type":"thread.started"}'; echo 'stream error: 503 Service Unavailable' >&2; exit 1"#)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        let pattern = regex::Regex::new(&ServerConfig::default().transient_error_pattern).unwrap();
+        let options = StreamOptions {
+            transient_error: Some(&pattern),
+            ..StreamOptions::for_test(&redactor, &shutdown, &cancel)
+        };
+
+        let exit = process_streams(child, tx, capture, home.path(), &options).await.unwrap();
+
+        assert_eq!(exit, StreamExit::Transient { exit_code: 1, reason: "stream error: 503 Service Unavailable".to_string(), codex_session_id: None });
+        while let Some(Ok(response)) = rx.recv().await {
+            assert!(!matches!(response.event, Some(Event::TaskComplete(_))), "retried attempts must not complete the task");
+        }
+    }
+
+    #[test]
+    fn retry_backoff_doubles_per_attempt_up_to_a_minute() {
+        assert_eq!(retry_backoff(1000, 1), Duration::from_secs(1));
+        assert_eq!(retry_backoff(1000, 2), Duration::from_secs(2));
+        assert_eq!(retry_backoff(1000, 3), Duration::from_secs(4));
+        assert_eq!(retry_backoff(1000, 30), Duration::from_secs(60));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn retry_resumes_the_rollout_without_resending_the_prompt() {
+        use std::os::unix::fs::PermissionsExt;
+        let bin_dir = tempfile::tempdir().unwrap();
+        let codex = bin_dir.path().join("codex");
+        // 第一次尝试写出 rollout 后以 503 失败，之后的尝试直接成功；每次尝试的参数与 stdin 分别记录
+        let script = format!(
+            r#"#!/bin/sh
+dir={dir}
+n=$(ls "$dir" | grep -c '^attempt-')
+echo "$@" > "$dir/args-$n"
+cat > "$dir/attempt-$n"
+if [ "$n" = 0 ]; then
+  mkdir -p "$CODEX_HOME/sessions/2025/01/02"
+  echo '{{"type":"session_meta","payload":{{"id":"t-1"}}}}' > "$CODEX_HOME/sessions/2025/01/02/rollout-2025-01-02T00-00-00-t-1.jsonl"
+  echo '{{"type":"thread.started"}}'
+  echo 'stream error: 503 Service Unavailable' >&2
+  exit 1
+fi
+echo '{{"type":"thread.started"}}'
+"#,
+            dir = bin_dir.path().display()
+        );
+        std::fs::write(&codex, script).unwrap();
+        std::fs::set_permissions(&codex, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = Arc::new(ServerConfig { codex_bin: codex, log_dir: None, retry_backoff_base_ms: 1, ..Default::default() });
+        let req = RunTaskRequest { session_id: "s1".to_string(), prompt: "fix the build".to_string(), max_retries: 1, ..Default::default() };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+
+        let exit_code = handle_run(req, false, config, TraceContext::default(), tx, CancellationToken::new(), CancellationToken::new()).await.unwrap();
+        let mut complete = None;
+        while let Some(Ok(response)) = rx.recv().await {
+            if let Some(Event::TaskComplete(event)) = response.event { complete = Some(event); }
+        }
+
+        assert_eq!(exit_code, Some(0));
+        // 开始时刻取自第一次尝试之前
+        let complete = complete.expect("no task_complete");
+        let (started, ended) = (complete.task_started_at.unwrap(), complete.task_ended_at.unwrap());
+        assert!((ended.seconds, ended.nanos) >= (started.seconds, started.nanos), "{started:?} > {ended:?}");
+        let read = |name: &str| std::fs::read_to_string(bin_dir.path().join(name)).unwrap();
+        assert_eq!(read("attempt-0"), "fix the build");
+        assert!(read("args-1").contains("resume t-1"));
+        assert_eq!(read("attempt-1"), RETRY_CONTINUATION_PROMPT);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn context_file_mode_makes_scripts_executable() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let script = File { path: "bin/run.sh".to_string(), content: b"#!/bin/sh\necho ok\n".to_vec(), mode: Some(0o755), ..Default::default() };
+        let image = File { path: "logo.png".to_string(), content: vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', 0xff], ..Default::default() };
+
+        write_context_file(&dir.path().join(&script.path), &script).await.unwrap();
+        write_context_file(&dir.path().join(&image.path), &image).await.unwrap();
+
+        let mode = std::fs::metadata(dir.path().join("bin/run.sh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o755);
+        assert_eq!(std::fs::read(dir.path().join("logo.png")).unwrap(), image.content);
+        let err = validate_request(&RunTaskRequest { prompt: "p".to_string(), context_files: vec![File { mode: Some(0o4755), ..script }], ..Default::default() });
+        assert!(matches!(err, Err(AdapterError::InvalidArgument(_))));
+    }
+
+    #[tokio::test]
+    async fn rollout_streamer_sends_only_newly_appended_lines() {
+        let home = tempfile::tempdir().unwrap();
+        let day = home.path().join("sessions/2025/01/02");
+        std::fs::create_dir_all(&day).unwrap();
+        let path = day.join("rollout-s1.jsonl");
+        std::fs::write(&path, "{\"old\":1}\n").unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+
+        let streamer = RolloutStreamer::spawn(home.path(), RolloutRedactor::default(), tx).await;
+        std::fs::write(&path, "{\"old\":1}\n{\"new\":2}\n{\"partial\"").unwrap();
+        streamer.finish().await;
+        drop(streamer);
+
+        let mut deltas = Vec::new();
+        while let Some(Ok(response)) = rx.recv().await {
+            if let Some(Event::RolloutDelta(delta)) = response.event { deltas.push(delta); }
+        }
+        assert_eq!(deltas.len(), 1);
+        assert_eq!(deltas[0].data, b"{\"new\":2}\n");
+        assert_eq!(deltas[0].offset, 10);
+        assert_eq!(deltas[0].file_name, "rollout-s1.jsonl");
+    }
+
+    #[test]
+    fn config_overrides_are_validated_and_passed_after_builtin_overrides() {
+        let req = RunTaskRequest {
+            prompt: "p".to_string(),
+            session_config: Some(SessionConfig { model: "gpt-5".to_string(), ..Default::default() }),
+            config_overrides: [("tools.web_search".to_string(), "true".to_string()), ("model".to_string(), "o3".to_string())].into(),
+            ..Default::default()
+        };
+        assert!(validate_request(&req).is_ok());
+        let cmd = build_codex_command(&req, &ServerConfig::default(), Path::new("/tmp/home"), Path::new("/tmp"), &TraceContext::default(), None, None);
+        let args: Vec<_> = cmd.as_std().get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+        let exec = args.iter().position(|a| a == "exec").unwrap();
+        assert_eq!(args[..exec], ["-c", "model=gpt-5", "-c", "model=o3", "-c", "tools.web_search=true"]);
+
+        let managed = ["approval_policy", "sandbox_mode", "sandbox_workspace_write.network_access", "mcp_servers.evil.command", "model_providers.x.base_url", "history.persistence", "model_reasoning_effort"];
+        for key in managed {
+            let bad = RunTaskRequest { config_overrides: [(key.to_string(), "x".to_string())].into(), ..req.clone() };
+            assert!(matches!(validate_request(&bad), Err(AdapterError::InvalidArgument(_))), "{key:?}");
+        }
+        assert!(!is_managed_config_key("historyx") && !is_managed_config_key("sandbox_modes"));
+
+        for (key, value) in [("a;rm -rf /", "x"), ("key with space", "x"), ("", "x"), (".hidden", "x"), ("ok", "line\nbreak")] {
+            let bad = RunTaskRequest { config_overrides: [(key.to_string(), value.to_string())].into(), ..req.clone() };
+            assert!(matches!(validate_request(&bad), Err(AdapterError::InvalidArgument(_))), "{key:?}");
+        }
+    }
+
+    #[test]
+    fn dry_run_reports_config_command_and_context_files_without_touching_disk() {
+        let base = tempfile::tempdir().unwrap();
+        let req = RunTaskRequest {
+            session_id: "s1".to_string(),
+            prompt: "p".to_string(),
+            base_dir: base.path().display().to_string(),
+            session_config: Some(SessionConfig { model: "gpt-5".to_string(), ..Default::default() }),
+            context_files: vec![
+                File { path: "src\\lib.rs".to_string(), ..Default::default() },
+                File { path: "../escape.txt".to_string(), ..Default::default() },
+            ],
+            dry_run: true,
+            ..Default::default()
+        };
+        let config = ServerConfig::default();
+
+        let dry_run = build_dry_run(&req, &config, &TraceContext::default()).unwrap();
+
+        assert!(dry_run.config_toml.contains("model = \"gpt-5\""));
+        assert_eq!(dry_run.argv[..3], ["codex", "-c", "model=gpt-5"]);
+        assert!(dry_run.argv.contains(&"exec".to_string()));
+        assert_eq!(dry_run.work_dir, base.path().display().to_string());
+        assert_eq!(dry_run.context_files, ["src/lib.rs"]);
+        assert_eq!(std::fs::read_dir(base.path()).unwrap().count(), 0);
+    }
+
+    #[tokio::test]
+    async fn dry_run_applies_the_same_pre_spawn_checks() {
+        let req = RunTaskRequest {
+            prompt: "p".to_string(),
+            context_files: vec![File { path: "big.bin".to_string(), content: vec![0; 64], ..Default::default() }],
+            dry_run: true,
+            ..Default::default()
+        };
+        let config = Arc::new(ServerConfig { max_context_file_bytes: 16, ..Default::default() });
+        let (tx, _rx) = tokio::sync::mpsc::channel(100);
+
+        let result = handle_run(req, false, config, TraceContext::default(), tx, CancellationToken::new(), CancellationToken::new()).await;
+        assert!(result.is_err(), "oversized context files must fail the dry run too");
+    }
+
+    #[test]
+    fn interactive_approval_policies_are_rejected_in_exec_mode() {
+        let req = |policy: agent::ApprovalPolicy| RunTaskRequest {
+            prompt: "fix the build".to_string(),
+            session_config: Some(SessionConfig { approval_policy: policy as i32, ..Default::default() }),
+            ..Default::default()
+        };
+        assert!(validate_request(&req(agent::ApprovalPolicy::OnRequest)).is_err());
+        assert!(validate_request(&req(agent::ApprovalPolicy::UnlessTrusted)).is_err());
+
+        let never = req(agent::ApprovalPolicy::Never);
+        assert!(validate_request(&never).is_ok());
+        let cmd = build_codex_command(&never, &ServerConfig::default(), Path::new("/tmp/home"), Path::new("/tmp"), &TraceContext::default(), None, None);
+        let args: Vec<_> = cmd.as_std().get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+        assert_eq!(args[..2], ["-c", "approval_policy=never"]);
+        assert_eq!(args.last().map(String::as_str), Some("-"));
+    }
+
+    #[test]
+    fn sandbox_workspace_write_overrides_precede_the_exec_subcommand() {
+        let req = RunTaskRequest {
+            prompt: "hi".to_string(),
+            session_config: Some(SessionConfig {
+                sandbox_policy: SandboxPolicy::WorkspaceWrite as i32,
+                network_access: true,
+                writable_roots: vec!["/data".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let cmd = build_codex_command(&req, &ServerConfig::default(), Path::new("/tmp/home"), Path::new("/tmp"), &TraceContext::default(), None, None);
+        let args: Vec<_> = cmd.as_std().get_args().map(|a| a.to_string_lossy().into_owned()).collect();
+        let exec = args.iter().position(|a| a == "exec").unwrap();
+        let network = args.iter().position(|a| a == "sandbox_workspace_write.network_access=true").unwrap();
+        let roots = args.iter().position(|a| a == r#"sandbox_workspace_write.writable_roots=["/data"]"#).unwrap();
+        assert!(network < exec && roots < exec, "{args:?}");
+        assert_eq!(args[exec + 3..exec + 5], ["--sandbox", "workspace-write"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn stderr_forwarding_is_capped_and_tail_reported_on_failure() {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"echo '{"type":"thread.started"}'; for i in 1 2 3 4 5 6; do echo "debug $i" >&2; done; exit 3"#)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        let options = StreamOptions {
+            stderr_limits: StderrLimits { max_lines: Some(2), max_bytes: None },
+            ..StreamOptions::for_test(&redactor, &shutdown, &cancel)
+        };
+
+        process_streams(child, tx, capture, home.path(), &options).await.unwrap();
+
+        let (mut logs, mut errors) = (Vec::new(), Vec::new());
+        while let Some(Ok(response)) = rx.recv().await {
+            match response.event {
+                Some(Event::AdapterLog(line)) => logs.push(line),
+                Some(Event::Error(message)) => errors.push(message),
+                _ => {}
+            }
+        }
+        assert_eq!(logs.len(), 3);
+        assert_eq!(logs[..2], ["[STDERR] debug 1", "[STDERR] debug 2"]);
+        assert!(logs[2].contains("truncated after 2 lines"));
+        assert_eq!(errors.len(), 1);
+        assert!(errors[0].starts_with("codex exited with code 3") && errors[0].ends_with("debug 5\ndebug 6"), "{}", errors[0]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cancel_kills_codex_that_ignores_sigterm_after_grace() {
+        // 忽略 SIGTERM，只能在宽限期后被 SIGKILL
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"trap '' TERM; echo '{"type":"thread.started"}'; sleep 30"#)
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        let options = StreamOptions {
+            cancel_grace: Duration::from_millis(300),
+            ..StreamOptions::for_test(&redactor, &shutdown, &cancel)
+        };
+        let canceller = cancel.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            canceller.cancel();
+        });
+
+        let exit = tokio::time::timeout(Duration::from_secs(10), process_streams(child, tx, capture, home.path(), &options))
+            .await
+            .expect("codex was not killed after the grace period")
+            .unwrap();
+
+        assert_eq!(exit, StreamExit::Finished(None));
+        let mut events = Vec::new();
+        while let Some(Ok(response)) = rx.recv().await {
+            match response.event {
+                Some(Event::Cancelled(cancelled)) => events.push(format!("cancelled forced={}", cancelled.forced)),
+                Some(Event::TaskComplete(complete)) => events.push(format!("complete success={}", complete.success)),
+                _ => {}
+            }
+        }
+        assert_eq!(events, ["cancelled forced=true", "complete success=false"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn task_timeout_applies_before_the_first_codex_event() {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg("sleep 30")
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, _rx) = tokio::sync::mpsc::channel(100);
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        // 任务超时短于首行超时
+        let options = StreamOptions {
+            task_timeout: Some(Duration::from_millis(300)),
+            ..StreamOptions::for_test(&redactor, &shutdown, &cancel)
+        };
+
+        let err = tokio::time::timeout(Duration::from_secs(5), process_streams(child, tx, capture, home.path(), &options))
+            .await
+            .expect("task timeout ignored before the first event")
+            .unwrap_err();
+        assert!(err.to_string().contains("before codex produced any output"), "{err}");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn shutdown_kills_codex_before_its_first_event() {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg("sleep 30")
+            .process_group(0)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        let options = StreamOptions::for_test(&redactor, &shutdown, &cancel);
+        let shutter = shutdown.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            shutter.cancel();
+        });
+
+        let exit = tokio::time::timeout(Duration::from_secs(5), process_streams(child, tx, capture, home.path(), &options))
+            .await
+            .expect("shutdown waited for the first-event timeout")
+            .unwrap();
+
+        assert_eq!(exit, StreamExit::Finished(None));
+        let mut events = Vec::new();
+        while let Some(Ok(response)) = rx.recv().await {
+            match response.event {
+                Some(Event::Error(message)) => events.push(message),
+                Some(Event::TaskComplete(complete)) => events.push(format!("complete success={}", complete.success)),
+                _ => {}
+            }
+        }
+        assert_eq!(events, ["adapter is shutting down", "complete success=false"]);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn heartbeats_and_duration_warnings_cover_codex_startup() {
+        let child = Command::new("sh")
+            .arg("-c")
+            .arg(r#"sleep 0.5; echo '{"type":"thread.started"}'"#)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .unwrap();
+        let home = tempfile::tempdir().unwrap();
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let capture = capture::capture_strategy("", tx.clone(), home.path(), false).await.unwrap();
+        let redactor = RolloutRedactor::compile(&[]).unwrap();
+        let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+        let options = StreamOptions {
+            heartbeat_interval: Some(Duration::from_millis(100)),
+            warn_after: Some(Duration::from_millis(200)),
+            ..StreamOptions::for_test(&redactor, &shutdown, &cancel)
+        };
+
+        process_streams(child, tx, capture, home.path(), &options).await.unwrap();
+
+        let (mut heartbeats, mut warnings) = (0, Vec::new());
+        while let Some(Ok(response)) = rx.recv().await {
+            match response.event {
+                Some(Event::CodexEventJson(_)) => break,
+                Some(Event::Heartbeat(_)) => heartbeats += 1,
+                Some(Event::TaskDurationWarning(warning)) => warnings.push(warning.events_so_far),
+                _ => {}
+            }
+        }
+        assert!(heartbeats > 0, "no heartbeat before the first event");
+        assert!(!warnings.is_empty() && warnings.iter().all(|&n| n == 0), "{warnings:?}");
+    }
+
+    #[test]
+    fn typed_events_map_known_codex_events() {
+        let typed = |line: &str| events::typed_event(&serde_json::from_str(line).unwrap());
+        assert_eq!(
+            typed(r#"{"id":"1","msg":{"type":"agent_message","message":"done"}}"#),
+            Some(Event::AgentMessage(agent::AgentMessage { text: "done".to_string() }))
+        );
+        assert_eq!(
+            typed(r#"{"id":"1","msg":{"type":"agent_message_delta","delta":"do"}}"#),
+            Some(Event::AgentMessageDelta(agent::AgentMessageDelta { delta: "do".to_string() }))
+        );
+        assert_eq!(
+            typed(r#"{"type":"exec_command_begin","call_id":"c1","command":["ls","-la"],"cwd":"/w"}"#),
+            Some(Event::ExecCommandBegin(agent::ExecCommandBegin { call_id: "c1".to_string(), command: vec!["ls".to_string(), "-la".to_string()], cwd: "/w".to_string() }))
+        );
+        assert_eq!(
+            typed(r#"{"type":"exec_command_end","call_id":"c1","exit_code":2,"stdout":"out","stderr":"err"}"#),
+            Some(Event::ExecCommandEnd(agent::ExecCommandEnd { call_id: "c1".to_string(), exit_code: Some(2), stdout: "out".to_string(), stderr: "err".to_string() }))
+        );
+        assert_eq!(
+            typed(r#"{"type":"patch_apply_begin","call_id":"p1","auto_approved":true,"changes":{"src/a.rs":{"add":{"content":""}}}}"#),
+            Some(Event::PatchApply(agent::PatchApply { call_id: "p1".to_string(), files: vec!["src/a.rs".to_string()], ..Default::default() }))
+        );
+        assert_eq!(
+            typed(r#"{"type":"patch_apply_end","call_id":"p1","success":true,"stdout":"applied","stderr":""}"#),
+            Some(Event::PatchApply(agent::PatchApply { call_id: "p1".to_string(), completed: true, success: true, stdout: "applied".to_string(), ..Default::default() }))
+        );
+        assert_eq!(
+            typed(r#"{"type":"task_complete","last_agent_message":"done"}"#),
+            Some(Event::CodexTaskComplete(agent::CodexTaskComplete { last_agent_message: "done".to_string() }))
+        );
+        assert_eq!(typed(r#"{"type":"error","message":"boom"}"#), Some(Event::CodexError(agent::CodexError { message: "boom".to_string() })));
+        // exec --json 中对应的 item 事件
+        assert_eq!(
+            typed(r#"{"type":"item.completed","item":{"id":"item_1","type":"command_execution","command":"ls","aggregated_output":"a\n","exit_code":0,"status":"completed"}}"#),
+            Some(Event::ExecCommandEnd(agent::ExecCommandEnd { call_id: "item_1".to_string(), exit_code: Some(0), stdout: "a\n".to_string(), stderr: String::new() }))
+        );
+        assert_eq!(
+            typed(r#"{"type":"item.completed","item":{"id":"item_2","type":"file_change","changes":[{"path":"a.rs","kind":"update"}],"status":"failed"}}"#),
+            Some(Event::PatchApply(agent::PatchApply { call_id: "item_2".to_string(), completed: true, files: vec!["a.rs".to_string()], ..Default::default() }))
+        );
+        assert_eq!(typed(r#"{"type":"turn.started"}"#), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn typed_events_forward_unparsable_lines_raw_with_a_note() {
+        let completed = r#"{"type":"item.completed","item":{"id":"item_0","type":"agent_message","text":"hi"}}"#;
+        let script = format!(r#"echo '{completed}'; echo '{{"type":"turn.started"}}'; echo '{{"type":"agent_mess'"#);
+        // 事件过滤不影响无法解析的行，与 typed_events 无关
+        for (typed_events, expected) in [
+            (true, vec!["agent_message hi".to_string(), "note".to_string(), r#"raw {"type":"agent_mess"#.to_string()]),
+            (false, vec![format!("raw {completed}"), r#"raw {"type":"agent_mess"#.to_string()]),
+        ] {
+            let child = Command::new("sh")
+                .arg("-c")
+                .arg(&script)
+                .stdin(Stdio::null())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::piped())
+                .spawn()
+                .unwrap();
+            let home = tempfile::tempdir().unwrap();
+            let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+            let capture = capture::capture_strategy("", tx.clone(), home.path(), typed_events).await.unwrap();
+            let redactor = RolloutRedactor::compile(&[]).unwrap();
+            let (shutdown, cancel) = (CancellationToken::new(), CancellationToken::new());
+            let options = StreamOptions {
+                typed_events,
+                event_filter: &[CodexEventKind::ItemCompleted],
+                ..StreamOptions::for_test(&redactor, &shutdown, &cancel)
+            };
+
+            process_streams(child, tx, capture, home.path(), &options).await.unwrap();
+
+            let mut events = Vec::new();
+            while let Some(Ok(response)) = rx.recv().await {
+                match response.event {
+                    Some(Event::AgentMessage(message)) => events.push(format!("agent_message {}", message.text)),
+                    Some(Event::CodexEventJson(line)) => events.push(format!("raw {line}")),
+                    Some(Event::AdapterLog(line)) if line.contains("not valid JSON") => events.push("note".to_string()),
+                    _ => {}
+                }
+            }
+            assert_eq!(events, expected, "typed_events={typed_events}");
+        }
+    }
+
+    #[tokio::test]
+    async fn backpressure_relay_drops_only_low_priority_events() {
+        let (client, mut rx) = tokio::sync::mpsc::channel(2);
+        let tx = event_relay::spawn(client, 16);
+        for i in 0..10 {
+            tx.send(Ok(event_response(Event::CodexEventJson(format!("{{\"n\":{i}}}"))))).await.unwrap();
+        }
+        tx.send(Ok(event_response(Event::TaskComplete(TaskComplete { exit_code: 0, success: true, ..Default::default() })))).await.unwrap();
+        drop(tx);
+
+        let mut events = Vec::new();
+        while let Some(Ok(response)) = rx.recv().await {
+            events.push(response.event.unwrap());
+        }
+        assert!(matches!(events.last(), Some(Event::TaskComplete(_))));
+        let kept = events.iter().filter(|e| matches!(e, Event::CodexEventJson(_))).count() as u64;
+        let notices: Vec<_> = events.iter().filter_map(|e| match e { Event::AdapterLog(l) => Some(l.as_str()), _ => None }).collect();
+        let dropped: u64 = notices.iter().map(|l| l.split(' ').nth(1).unwrap().parse::<u64>().unwrap()).sum();
+        assert!(kept < 10, "a two-slot client channel cannot hold every event");
+        assert_eq!(kept + dropped, 10);
+    }
+
+    #[tokio::test]
+    async fn revived_rollout_is_placed_under_its_original_date() {
+        let created = chrono::Utc::now() - chrono::Duration::days(1);
+        let id = "0199a213-81c0-7800-8aa1-bbab2a035a53";
+        let history = format!(
+            "{{\"timestamp\":\"{ts}\",\"type\":\"session_meta\",\"payload\":{{\"id\":\"{id}\",\"timestamp\":\"{ts}\"}}}}\n",
+            ts = created.to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        );
+
+        let path = rollout::revival_path(history.as_bytes(), id, chrono::Local::now());
+
+        let local = created.with_timezone(&chrono::Local);
+        let expected_dir = local.format("sessions/%Y/%m/%d").to_string();
+        assert_eq!(path.parent().unwrap(), Path::new(&expected_dir));
+        assert_eq!(path.file_name().unwrap().to_string_lossy(), format!("rollout-{}-{id}.jsonl", local.format("%Y-%m-%dT%H-%M-%S")));
+
+        // 与 codex 按 id 查找的方式一致：位于 sessions 下、文件名以 <id>.jsonl 结尾
+        let home = TempDir::new().unwrap();
+        std::fs::create_dir_all(home.path().join(path.parent().unwrap())).unwrap();
+        std::fs::write(home.path().join(&path), &history).unwrap();
+        let found = extract_updated_rollout(home.path(), id).await.unwrap().unwrap();
+        assert_eq!(found.path, home.path().join(&path));
+
+        let undated = rollout::revival_path(b"{\"type\":\"session_meta\",\"payload\":{\"id\":\"x\"}}\n", "x", created);
+        assert!(undated.starts_with(created.format("sessions/%Y/%m/%d").to_string()));
+
+        // UTC 时间戳换算到本地时区后可能落在另一天
+        let east8 = chrono::FixedOffset::east_opt(8 * 3600).unwrap();
+        let history = b"{\"timestamp\":\"2025-01-01T20:00:00.000Z\",\"type\":\"session_meta\",\"payload\":{\"id\":\"x\"}}\n";
+        let shifted = rollout::revival_path(history, "x", chrono::Utc::now().with_timezone(&east8));
+        assert_eq!(shifted, Path::new("sessions/2025/01/02/rollout-2025-01-02T04-00-00-x.jsonl"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn cpu_time_limit_kills_child_and_is_classified() {
+        let limits = ResourceLimits { cpu_time_sec: Some(1), ..Default::default() };
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg("while :; do :; done");
+        limits.apply(&mut cmd);
+
+        let mut child = cmd.spawn().unwrap();
+        let cpu_used = tokio::time::timeout(Duration::from_secs(30), exited_child_cpu_time(&child)).await.expect("cpu limit not enforced");
+        let status = child.wait().await.unwrap();
+        #[cfg(target_os = "linux")]
+        assert!(cpu_used.is_some_and(|used| used >= Duration::from_millis(900)), "{cpu_used:?}");
+
+        let error = limits.exhausted(&status, cpu_used, &[]).expect("exit should be attributed to the cpu limit");
+        assert_eq!(error.kind(), ErrorKind::CpuLimitExceeded);
+        assert!(error.message.contains("CPU time limit of 1s"), "{}", error.message);
+        assert_eq!(ResourceLimits::default().exhausted(&status, cpu_used, &[]), None);
+
+        // SIGKILL 只有在 codex 自身的 CPU 时间达到硬上限时才归因于 cpu_time_sec
+        use std::os::unix::process::ExitStatusExt;
+        let killed = std::process::ExitStatus::from_raw(libc::SIGKILL);
+        assert_eq!(limits.exhausted(&killed, Some(Duration::from_secs(6)), &[]).unwrap().kind(), ErrorKind::CpuLimitExceeded);
+        for cpu_used in [Some(Duration::from_millis(200)), None] {
+            let error = limits.exhausted(&killed, cpu_used, &[]).unwrap();
+            assert_eq!(error.kind(), ErrorKind::Unknown);
+            assert!(error.message.contains("killed by SIGKILL"), "{}", error.message);
+        }
+        let oom = ResourceLimits { mem_limit_mb: Some(64), ..Default::default() };
+        let abort = std::process::ExitStatus::from_raw(libc::SIGABRT);
+        let tail = ["memory allocation of 1048576 bytes failed".to_string()];
+        assert_eq!(oom.exhausted(&abort, None, &tail).unwrap().kind(), ErrorKind::MemoryLimitExceeded);
+    }
+
+    #[test]
+    fn full_prompt_orders_sections_from_system_to_user_prompt() {
+        assert_eq!(build_full_prompt("fix it", None), "fix it");
+        let config = SessionConfig {
+            system_prompt: Some("system".to_string()),
+            instructions: Some("instructions".to_string()),
+            developer_instructions: Some("developer".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(build_full_prompt("fix it", Some(&config)), "system\n\ninstructions\n\ndeveloper\n\nfix it");
+
+        let custom = SessionConfig { prompt_section_delimiter: Some("\n---\n".to_string()), ..config };
+        assert_eq!(build_full_prompt("fix it", Some(&custom)), "system\n---\ninstructions\n---\ndeveloper\n---\nfix it");
+    }
+
+    #[test]
+    fn full_prompt_skips_exact_duplicate_sections() {
+        let config = SessionConfig {
+            system_prompt: Some("be terse".to_string()),
+            instructions: Some("be terse".to_string()),
+            developer_instructions: Some("use rustfmt".to_string()),
+            ..Default::default()
+        };
+        // 客户端同时把 instructions 写进了 prompt
+        assert_eq!(build_full_prompt("use rustfmt", Some(&config)), "be terse\n\nuse rustfmt");
+        // 仅内容相近的段落不视为重复
+        assert_eq!(build_full_prompt("be terse.", Some(&config)), "be terse\n\nuse rustfmt\n\nbe terse.");
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn task_started_precedes_codex_output() {
+        use std::os::unix::fs::PermissionsExt;
+        let bin_dir = tempfile::tempdir().unwrap();
+        let codex = bin_dir.path().join("codex");
+        std::fs::write(&codex, "#!/bin/sh\ncat >/dev/null\necho '{\"type\":\"thread.started\"}'\n").unwrap();
+        std::fs::set_permissions(&codex, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let config = Arc::new(ServerConfig { codex_bin: codex, log_dir: None, ..Default::default() });
+        let req = RunTaskRequest {
+            session_id: "s1".to_string(),
+            prompt: "hi".to_string(),
+            session_config: Some(SessionConfig { model: "gpt-5".to_string(), sandbox_policy: SandboxPolicy::ReadOnly as i32, ..Default::default() }),
+            ..Default::default()
+        };
+        let (tx, mut rx) = tokio::sync::mpsc::channel(100);
+        let exit_code = handle_run(req, false, config, TraceContext::default(), tx, CancellationToken::new(), CancellationToken::new()).await.unwrap();
+
+        assert_eq!(exit_code, Some(0));
+        let mut events = Vec::new();
+        while let Some(Ok(response)) = rx.recv().await {
+            match response.event {
+                Some(Event::TaskStarted(started)) => events.push(format!("started:{}:{}:{}", started.model, started.sandbox_policy, started.resume)),
+                Some(Event::CodexEventJson(_)) => events.push("codex".to_string()),
+                Some(Event::TaskComplete(_)) => events.push("complete".to_string()),
+                _ => {}
+            }
+        }
+        assert_eq!(events, [format!("started:gpt-5:{}:false", SandboxPolicy::ReadOnly as i32), "codex".to_string(), "complete".to_string()]);
+    }
+
+    #[test]
+    fn startup_failures_are_classified_from_stderr() {
+        use events::classify_failure;
+        assert_eq!(classify_failure(&["Error: unexpected status 401 Unauthorized: Incorrect API key provided"]), ErrorKind::AuthError);
+        assert_eq!(classify_failure(&["stream error: 429 Too Many Requests", "You exceeded your current quota"]), ErrorKind::QuotaExceeded);
+        assert_eq!(classify_failure(&["thread 'main' panicked at core/src/lib.rs"]), ErrorKind::Unknown);
+        assert_eq!(classify_failure(&["connection refused: 127.0.0.1:4010", "read 14290 bytes"]), ErrorKind::Unknown);
+        assert_eq!(classify_failure(&["authentication succeeded for user"]), ErrorKind::Unknown);
+        assert_eq!(classify_failure(&["Error: authentication failed"]), ErrorKind::AuthError);
+        assert_eq!(classify_failure(&[]), ErrorKind::Unknown);
+    }
+
+    #[tokio::test]
+    async fn second_concurrent_run_of_a_session_is_rejected() {
+        let service = MyAgentService::new(ServerConfig { codex_bin: PathBuf::from("/nonexistent/codex"), ..Default::default() });
+        let request = |force: bool| Request::new(RunTaskRequest { session_id: "dup".to_string(), prompt: "hi".to_string(), force, ..Default::default() });
+
+        // 单线程运行时下第一个任务尚未被调度，会话仍处于登记状态
+        let _first = service.run_task(request(false)).await.unwrap();
+        let second = service.run_task(request(false)).await.unwrap_err();
+        assert_eq!(second.code(), tonic::Code::AlreadyExists);
+        assert!(service.run_task(request(true)).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn list_providers_caches_live_model_lists_and_falls_back_to_empty() {
+        use tokio::io::AsyncReadExt;
+        // 极简 HTTP 服务：记录请求次数并返回 OpenAI 风格的模型列表
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let hits = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = hits.clone();
+        tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = socket.read(&mut [0u8; 4096]).await;
+                let body = r#"{"data":[{"id":"gpt-5"},{"id":"gpt-5-mini"}]}"#;
+                let response = format!("HTTP/1.1 200 OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{body}", body.len());
+                let _ = socket.write_all(response.as_bytes()).await;
+            }
+        });
+        let dir = tempfile::tempdir().unwrap();
+        std::fs::write(
+            dir.path().join("live.toml"),
+            format!("model = \"gpt-5\"\n[provider_info]\nname = \"live\"\nbase_url = \"http://{addr}/v1\"\nmodel_list_endpoint = \"http://{addr}/v1/models\"\n"),
+        )
+        .unwrap();
+        std::fs::write(dir.path().join("down.toml"), "model = \"m1\"\n[provider_info]\nname = \"down\"\nmodel_list_endpoint = \"http://127.0.0.1:1/models\"\n").unwrap();
+        std::fs::write(dir.path().join("no-provider.toml"), "model = \"o3\"\n").unwrap();
+        let service = MyAgentService::new(ServerConfig { profiles_dir: Some(dir.path().to_path_buf()), ..Default::default() });
+        let list = |refresh_model_cache| service.list_providers(Request::new(ListProvidersRequest { refresh_model_cache }));
+
+        let providers = list(false).await.unwrap().into_inner().providers;
+        let summary: Vec<_> = providers.iter().map(|p| (p.profile.as_str(), p.model_suggestions.clone())).collect();
+        assert_eq!(summary, [("down", vec!["m1".to_string()]), ("live", vec!["gpt-5".to_string(), "gpt-5-mini".to_string()])]);
+        assert_eq!(providers[1].base_url, format!("http://{addr}/v1"));
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+
+        // 缓存命中不再请求；refresh_model_cache 强制重新获取
+        list(false).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 1);
+        list(true).await.unwrap();
+        assert_eq!(hits.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn task_complete_reports_when_the_persistent_workspace_expires() {
+        let started = now_timestamp();
+        let complete = task_complete(0, true, TaskCompletion { task_started_at: &started, workspace_ttl: Some(Duration::from_secs(3600)) });
+        let (ended, expires) = (complete.task_ended_at.unwrap(), complete.workspace_expires_at.unwrap());
+        assert_eq!(expires, prost_types::Timestamp { seconds: ended.seconds + 3600, nanos: 0 });
+
+        let complete = task_complete(0, true, TaskCompletion { task_started_at: &started, workspace_ttl: None });
+        assert_eq!(complete.workspace_expires_at, None);
+    }
+
+    #[test]
+    fn task_registry_stamps_the_end_of_finished_tasks() {
+        let registry = Arc::new(TaskRegistry::new(10));
+        let mut finished = registry.start("done", String::new(), String::new());
+        let _running = registry.start("running", String::new(), String::new());
+        finished.set_final_state(TaskState::Completed);
+        drop(finished);
+
+        let tasks = registry.snapshot();
+        assert_eq!(tasks[0].session_id, "running");
+        assert_eq!(tasks[0].ended_at, None);
+        let (started, ended) = (tasks[1].started_at.clone().unwrap(), tasks[1].ended_at.clone().unwrap());
+        assert!((ended.seconds, ended.nanos) >= (started.seconds, started.nanos), "{started:?} > {ended:?}");
+    }
+
+    #[tokio::test]
+    async fn cancel_task_cancels_running_sessions_without_an_admin_token() {
+        let service = MyAgentService::new(ServerConfig { codex_bin: PathBuf::from("/nonexistent/codex"), ..Default::default() });
+        let run = Request::new(RunTaskRequest { session_id: "stop-me".to_string(), prompt: "hi".to_string(), ..Default::default() });
+        let cancel = |session_id: &str| Request::new(CancelTaskRequest { session_id: session_id.to_string() });
+
+        // 单线程运行时下任务尚未被调度，会话仍处于登记状态
+        let _stream = service.run_task(run).await.unwrap();
+        let response = service.cancel_task(cancel("stop-me")).await.unwrap().into_inner();
+        assert!(response.found);
+        assert!(service.active_sessions.get("stop-me").unwrap().cancel.is_cancelled());
+
+        let unknown = service.cancel_task(cancel("unknown")).await.unwrap_err();
+        assert_eq!(unknown.code(), tonic::Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn list_sessions_filters_by_namespace() {
+        let config = ServerConfig { codex_bin: PathBuf::from("/nonexistent/codex"), admin_token: Some("admin".to_string()), ..Default::default() };
+        let service = MyAgentService::new(config);
+        for (session_id, namespace) in [("a1", "team-a"), ("b1", "team-b"), ("flat", "")] {
+            let run = RunTaskRequest { session_id: session_id.to_string(), session_namespace: namespace.to_string(), prompt: "hi".to_string(), ..Default::default() };
+            // 单线程运行时下任务尚未被调度，会话仍处于登记状态
+            service.run_task(Request::new(run)).await.unwrap();
+        }
+        let list = |filter_namespace: &str| {
+            let mut request = Request::new(ListSessionsRequest { filter_namespace: filter_namespace.to_string() });
+            request.metadata_mut().insert(auth::ADMIN_TOKEN_HEADER, "admin".parse().unwrap());
+            service.list_sessions(request)
+        };
+
+        let sessions = list("team-a").await.unwrap().into_inner().sessions;
+        assert_eq!(sessions.iter().map(|s| (s.session_id.as_str(), s.session_namespace.as_str())).collect::<Vec<_>>(), [("a1", "team-a")]);
+        let mut all: Vec<_> = list("").await.unwrap().into_inner().sessions.into_iter().map(|s| s.session_id).collect();
+        all.sort();
+        assert_eq!(all, ["a1", "b1", "flat"]);
+    }
+
+    #[tokio::test]
+    async fn compact_rollout_requires_an_admin_token_and_hides_server_paths() {
+        let root = tempfile::tempdir().unwrap();
+        let config = ServerConfig {
+            admin_token: Some("admin".to_string()),
+            codex_home_template: Some("{session_id}".to_string()),
+            codex_home_root: Some(root.path().to_path_buf()),
+            ..Default::default()
+        };
+        let service = MyAgentService::new(config);
+        let compact = |token: Option<&str>| {
+            let mut request = Request::new(CompactRolloutRequest { session_id: "s1".to_string(), keep_last_n_turns: 1, ..Default::default() });
+            if let Some(token) = token { request.metadata_mut().insert(auth::ADMIN_TOKEN_HEADER, token.parse().unwrap()); }
+            service.compact_rollout(request)
+        };
+
+        assert_eq!(compact(None).await.unwrap_err().code(), tonic::Code::PermissionDenied);
+        let missing = compact(Some("admin")).await.unwrap_err();
+        assert_eq!(missing.code(), tonic::Code::NotFound);
+        assert!(!missing.message().contains(&root.path().display().to_string()), "{}", missing.message());
+    }
+
+    #[test]
+    fn validate_rollout_reports_session_id_and_bad_lines() {
+        let meta = r#"{"timestamp":"t","type":"session_meta","payload":{"id":"abcd"}}"#;
+        let item = r#"{"timestamp":"t","type":"response_item","payload":{}}"#;
+        let info = rollout::validate_rollout(format!("{meta}\n{item}\n").as_bytes()).unwrap();
+        assert_eq!(info, rollout::RolloutInfo { session_id: "abcd".to_string(), lines: 2 });
+
+        let err = |data: &[u8]| rollout::validate_rollout(data).unwrap_err().to_string();
+        assert!(err(b"\n  \n").contains("is empty"));
+        assert!(err(format!("{meta}\n{{\"truncated\":").as_bytes()).contains("line 2"));
+        assert!(err(&[meta.as_bytes(), b"\n\xff\xfe"].concat()).contains("line 2: invalid UTF-8"));
+        assert!(err(item.as_bytes()).contains("first record carries no session id"));
+    }
+
+    #[test]
+    fn tls_config_requires_both_cert_and_key() {
+        assert!(load_tls_config(None, None).unwrap().is_none());
+        assert!(load_tls_config(Some(Path::new("cert.pem")), None).is_err());
+        assert!(load_tls_config(None, Some(Path::new("key.pem"))).is_err());
+    }
+
+    #[tokio::test]
+    async fn server_accepts_tls_handshake_with_self_signed_cert() {
+        let cert = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        let (cert_pem, key_pem) = (cert.serialize_pem().unwrap(), cert.serialize_private_key_pem());
+        let dir = tempfile::tempdir().unwrap();
+        let (cert_path, key_path) = (dir.path().join("cert.pem"), dir.path().join("key.pem"));
+        std::fs::write(&cert_path, &cert_pem).unwrap();
+        std::fs::write(&key_path, &key_pem).unwrap();
+        let tls = load_tls_config(Some(&cert_path), Some(&key_path)).unwrap().unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::builder()
+            .tls_config(tls)
+            .unwrap()
+            .add_service(AgentServiceServer::new(MyAgentService::new(ServerConfig::default())))
+            .serve_with_incoming(tokio_stream::wrappers::TcpListenerStream::new(listener));
+        let server = tokio::spawn(server);
+
+        let client_tls = tonic::transport::ClientTlsConfig::new()
+            .ca_certificate(tonic::transport::Certificate::from_pem(&cert_pem))
+            .domain_name("localhost");
+        let channel = tonic::transport::Channel::from_shared(format!("https://{addr}"))
+            .unwrap()
+            .tls_config(client_tls)
+            .unwrap()
+            .connect()
+            .await;
+        assert!(channel.is_ok(), "TLS handshake failed: {:?}", channel.err());
+        server.abort();
+    }
+
+    #[tokio::test]
+    async fn session_log_lines_are_written_by_the_background_writer() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = SessionLog::open(dir.path(), "s1").unwrap();
+        let path = log.path().to_path_buf();
+        log.write("[STDOUT]", "one");
+        log.clone().write("[STDERR]", "two");
+        drop(log);
+
+        let mut text = String::new();
+        for _ in 0..50 {
+            text = std::fs::read_to_string(&path).unwrap();
+            if text.lines().count() == 2 { break; }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].ends_with(" [STDOUT] one") && lines[1].ends_with(" [STDERR] two"));
+    }
+
+    #[test]
+    fn codex_command_only_passes_allowlisted_host_env() {
+        let host = |name: &str| match name {
+            "ADAPTER_TEST_HOST_SECRET" => Some("leak".into()),
+            "ADAPTER_TEST_ALLOWED" => Some("ok".into()),
+            _ => None,
+        };
+        let req = RunTaskRequest {
+            session_id: "s1".to_string(),
+            env_vars: [("OPENAI_API_KEY".to_string(), "sk-test".to_string()), ("CODEX_ADAPTER_SESSION_ID".to_string(), "spoofed".to_string()), ("CODEX_HOME".to_string(), "/etc".to_string())].into(),
+            ..Default::default()
+        };
+        let config = ServerConfig { env_passthrough: vec!["ADAPTER_TEST_ALLOWED".to_string(), "ADAPTER_TEST_UNSET".to_string()], ..Default::default() };
+        let env = codex_env(&req, &config, Path::new("/tmp/home"), &TraceContext::default(), host);
+        let envs: std::collections::HashMap<_, _> = env.into_iter().filter_map(|(k, v)| Some((k.into_string().ok()?, v.into_string().ok()?))).collect();
+
+        assert!(!envs.contains_key("ADAPTER_TEST_HOST_SECRET"));
+        assert!(!envs.contains_key("ADAPTER_TEST_UNSET"));
+        assert_eq!(envs.get("CODEX_ADAPTER_SESSION_ID").map(String::as_str), Some("s1"));
+        assert_eq!(envs.get("ADAPTER_TEST_ALLOWED").map(String::as_str), Some("ok"));
+        assert_eq!(envs.get("OPENAI_API_KEY").map(String::as_str), Some("sk-test"));
+        assert_eq!(envs.get("CODEX_HOME").map(String::as_str), Some("/tmp/home"));
+        assert_eq!(envs.get("RUST_LOG").map(String::as_str), Some("info"));
+    }
+}
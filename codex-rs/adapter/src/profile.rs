@@ -0,0 +1,246 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use serde::Deserialize;
+use tracing::warn;
+
+use crate::agent::{ApprovalPolicy, HistoryPersistence, McpServerDef, ModelProviderInfo, SandboxPolicy, SessionConfig, WireApi};
+use crate::error::AdapterError;
+
+/// `{CODEX_HOME}/profiles/{name}.toml` 的内容：SessionConfig 的部分字段预设
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileToml {
+    model: Option<String>,
+    model_provider: Option<String>,
+    instructions: Option<String>,
+    developer_instructions: Option<String>,
+    system_prompt: Option<String>,
+    prompt_section_delimiter: Option<String>,
+    approval_policy: Option<ProfileApprovalPolicy>,
+    sandbox_policy: Option<ProfileSandboxPolicy>,
+    cwd: Option<String>,
+    model_reasoning_effort: Option<String>,
+    model_verbosity: Option<String>,
+    network_access: Option<bool>,
+    #[serde(default)]
+    writable_roots: Vec<String>,
+    auto_compact_token_limit: Option<u32>,
+    history_persistence: Option<ProfileHistoryPersistence>,
+    provider_info: Option<ProfileProviderInfo>,
+    #[serde(default)]
+    mcp_servers: HashMap<String, ProfileMcpServer>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileProviderInfo {
+    name: String,
+    base_url: Option<String>,
+    env_key: Option<String>,
+    wire_api: Option<ProfileWireApi>,
+    #[serde(default)]
+    http_headers: HashMap<String, String>,
+    #[serde(default)]
+    query_params: HashMap<String, String>,
+    #[serde(default)]
+    requires_openai_auth: bool,
+    connect_timeout_ms: Option<u32>,
+    read_timeout_ms: Option<u32>,
+    model_list_endpoint: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileMcpServer {
+    #[serde(rename = "type")]
+    server_type: String,
+    #[serde(default)]
+    command: String,
+    #[serde(default)]
+    args: Vec<String>,
+    #[serde(default)]
+    env: HashMap<String, String>,
+    #[serde(default)]
+    url: String,
+    startup_timeout_sec: Option<f64>,
+    tool_timeout_sec: Option<f64>,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ProfileWireApi {
+    Chat,
+    Responses,
+    ResponsesWebsocket,
+    Anthropic,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ProfileSandboxPolicy {
+    WorkspaceWrite,
+    ReadOnly,
+    DangerFullAccess,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ProfileHistoryPersistence {
+    SaveAll,
+    None,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ProfileApprovalPolicy {
+    Always,
+    Never,
+    UnlessTrusted,
+    OnRequest,
+    OnFailure,
+}
+
+/// 加载命名 profile 并与请求内联的 SessionConfig 合并 (内联字段优先)
+pub async fn apply_profile(profiles_dir: Option<&Path>, name: &str, inline: Option<SessionConfig>) -> Result<SessionConfig, AdapterError> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        return Err(AdapterError::InvalidArgument(format!("invalid config_profile name {name:?}")));
+    }
+    let Some(dir) = profiles_dir else {
+        return Err(AdapterError::NotFound(format!("config profile {name:?} not found: CODEX_HOME is not set")));
+    };
+    let path = dir.join(format!("{name}.toml"));
+    let raw = match tokio::fs::read_to_string(&path).await {
+        Ok(raw) => raw,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return Err(AdapterError::NotFound(format!("config profile {name:?} not found at {}", path.display())));
+        }
+        Err(e) => return Err(crate::error::map_io_error(e, &path)),
+    };
+    let profile: ProfileToml = toml::from_str(&raw)
+        .map_err(|e| AdapterError::InvalidArgument(format!("config profile {name:?} is not valid: {e}")))?;
+    Ok(merge(inline.unwrap_or_default(), profile))
+}
+
+/// ListProviders 展示的一个 profile 中的提供方
+#[derive(Debug)]
+pub struct ProviderProfile {
+    pub profile: String,
+    pub model: Option<String>,
+    pub provider: ModelProviderInfo,
+}
+
+/// profiles 目录中配置了 provider_info 的 profile，按 profile 名排序；无法解析的 profile 记录告警后跳过
+pub async fn provider_profiles(profiles_dir: &Path) -> Result<Vec<ProviderProfile>, AdapterError> {
+    let mut entries = match tokio::fs::read_dir(profiles_dir).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(crate::error::map_io_error(e, profiles_dir)),
+    };
+    let mut providers = Vec::new();
+    while let Some(entry) = entries.next_entry().await.map_err(|e| crate::error::map_io_error(e, profiles_dir))? {
+        let path = entry.path();
+        let Some(name) = path.file_name().and_then(|n| n.to_str()).and_then(|n| n.strip_suffix(".toml")) else { continue };
+        let parsed = tokio::fs::read_to_string(&path).await.map_err(anyhow::Error::from)
+            .and_then(|raw| toml::from_str::<ProfileToml>(&raw).map_err(anyhow::Error::from));
+        match parsed {
+            Ok(ProfileToml { model, provider_info: Some(provider), .. }) => {
+                providers.push(ProviderProfile { profile: name.to_string(), model, provider: provider_info(provider) });
+            }
+            Ok(_) => {}
+            Err(e) => warn!(path = %path.display(), error = %e, "Skipping config profile that cannot be read"),
+        }
+    }
+    providers.sort_by(|a, b| a.profile.cmp(&b.profile));
+    Ok(providers)
+}
+
+fn merge(mut merged: SessionConfig, profile: ProfileToml) -> SessionConfig {
+    fill(&mut merged.model, profile.model);
+    fill(&mut merged.model_provider, profile.model_provider);
+    fill(&mut merged.cwd, profile.cwd);
+    fill(&mut merged.model_reasoning_effort, profile.model_reasoning_effort);
+    fill(&mut merged.model_verbosity, profile.model_verbosity);
+    // proto3 的 bool 无法区分未设置与 false，因此 profile 只能开启 network_access
+    if !merged.network_access { merged.network_access = profile.network_access.unwrap_or(false); }
+    if merged.writable_roots.is_empty() { merged.writable_roots = profile.writable_roots; }
+    if merged.auto_compact_token_limit.is_none() { merged.auto_compact_token_limit = profile.auto_compact_token_limit; }
+    // 同理 HISTORY_PERSISTENCE_SAVE_ALL 即默认值，profile 只在内联配置仍为默认值时生效
+    if merged.history_persistence == HistoryPersistence::SaveAll as i32
+        && let Some(persistence) = profile.history_persistence
+    {
+        merged.history_persistence = match persistence {
+            ProfileHistoryPersistence::SaveAll => HistoryPersistence::SaveAll,
+            ProfileHistoryPersistence::None => HistoryPersistence::None,
+        } as i32;
+    }
+    if merged.instructions.is_none() { merged.instructions = profile.instructions; }
+    if merged.developer_instructions.is_none() { merged.developer_instructions = profile.developer_instructions; }
+    if merged.system_prompt.is_none() { merged.system_prompt = profile.system_prompt; }
+    if merged.prompt_section_delimiter.is_none() { merged.prompt_section_delimiter = profile.prompt_section_delimiter; }
+
+    if merged.approval_policy == ApprovalPolicy::Unspecified as i32
+        && let Some(policy) = profile.approval_policy
+    {
+        merged.approval_policy = match policy {
+            ProfileApprovalPolicy::Always => ApprovalPolicy::Always,
+            ProfileApprovalPolicy::Never => ApprovalPolicy::Never,
+            ProfileApprovalPolicy::UnlessTrusted => ApprovalPolicy::UnlessTrusted,
+            ProfileApprovalPolicy::OnRequest => ApprovalPolicy::OnRequest,
+            ProfileApprovalPolicy::OnFailure => ApprovalPolicy::OnFailure,
+        } as i32;
+    }
+    if merged.sandbox_policy == SandboxPolicy::Unspecified as i32
+        && let Some(policy) = profile.sandbox_policy
+    {
+        merged.sandbox_policy = match policy {
+            ProfileSandboxPolicy::WorkspaceWrite => SandboxPolicy::WorkspaceWrite,
+            ProfileSandboxPolicy::ReadOnly => SandboxPolicy::ReadOnly,
+            ProfileSandboxPolicy::DangerFullAccess => SandboxPolicy::DangerFullAccess,
+        } as i32;
+    }
+
+    if merged.provider_info.is_none() {
+        merged.provider_info = profile.provider_info.map(provider_info);
+    }
+
+    for (name, server) in profile.mcp_servers {
+        merged.mcp_servers.entry(name).or_insert(McpServerDef {
+            server_type: server.server_type,
+            command: server.command,
+            args: server.args,
+            env: server.env,
+            url: server.url,
+            startup_timeout_sec: server.startup_timeout_sec,
+            tool_timeout_sec: server.tool_timeout_sec,
+        });
+    }
+    merged
+}
+
+fn provider_info(p: ProfileProviderInfo) -> ModelProviderInfo {
+    ModelProviderInfo {
+        name: p.name,
+        base_url: p.base_url,
+        env_key: p.env_key,
+        experimental_bearer_token: None,
+        wire_api: match p.wire_api.unwrap_or(ProfileWireApi::Chat) {
+            ProfileWireApi::Chat => WireApi::Chat,
+            ProfileWireApi::Responses => WireApi::Responses,
+            ProfileWireApi::ResponsesWebsocket => WireApi::ResponsesWebsocket,
+            ProfileWireApi::Anthropic => WireApi::Anthropic,
+        } as i32,
+        http_headers: p.http_headers,
+        query_params: p.query_params,
+        requires_openai_auth: p.requires_openai_auth,
+        connect_timeout_ms: p.connect_timeout_ms,
+        read_timeout_ms: p.read_timeout_ms,
+        model_list_endpoint: p.model_list_endpoint.unwrap_or_default(),
+    }
+}
+
+fn fill(target: &mut String, value: Option<String>) {
+    if target.is_empty() && let Some(value) = value {
+        *target = value;
+    }
+}
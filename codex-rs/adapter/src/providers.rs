@@ -0,0 +1,71 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::warn;
+
+use crate::agent::{ModelProviderInfo, WireApi};
+
+// 获取在线模型列表的超时与缓存时长
+const MODEL_LIST_TIMEOUT: Duration = Duration::from_secs(5);
+const MODEL_LIST_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// 按 model_list_endpoint 缓存的在线模型列表；只缓存成功获取的结果，失败时下次请求重试
+#[derive(Debug, Default)]
+pub struct ModelListCache {
+    client: reqwest::Client,
+    entries: Mutex<HashMap<String, (Instant, Vec<String>)>>,
+}
+
+impl ModelListCache {
+    /// 提供方的在线模型列表；未设置 model_list_endpoint 时为空，获取失败时记录告警并返回空列表
+    pub async fn models(&self, provider: &ModelProviderInfo, refresh: bool) -> Vec<String> {
+        let endpoint = provider.model_list_endpoint.as_str();
+        if endpoint.is_empty() { return Vec::new(); }
+        if !refresh
+            && let Ok(entries) = self.entries.lock()
+            && let Some((fetched, models)) = entries.get(endpoint)
+            && fetched.elapsed() < MODEL_LIST_TTL
+        {
+            return models.clone();
+        }
+        match self.fetch(provider).await {
+            Ok(models) => {
+                if let Ok(mut entries) = self.entries.lock() {
+                    entries.insert(endpoint.to_string(), (Instant::now(), models.clone()));
+                }
+                models
+            }
+            Err(e) => {
+                warn!(provider = %provider.name, endpoint, error = %e, "Could not fetch the provider's model list");
+                Vec::new()
+            }
+        }
+    }
+
+    async fn fetch(&self, provider: &ModelProviderInfo) -> anyhow::Result<Vec<String>> {
+        let mut request = self.client.get(&provider.model_list_endpoint).timeout(MODEL_LIST_TIMEOUT);
+        for (name, value) in &provider.http_headers {
+            request = request.header(name, value);
+        }
+        // 与 codex 相同从 env_key 指定的环境变量读取 API key
+        if let Some(key) = provider.env_key.as_deref().and_then(|name| std::env::var(name).ok()) {
+            request = match provider.wire_api() {
+                WireApi::Anthropic => request.header("x-api-key", key).header("anthropic-version", "2023-06-01"),
+                _ => request.bearer_auth(key),
+            };
+        }
+        let body: serde_json::Value = request.send().await?.error_for_status()?.json().await?;
+        model_ids(&body).ok_or_else(|| anyhow::anyhow!("response has neither data[].id nor models[].name"))
+    }
+}
+
+// OpenAI / Anthropic 风格的 {"data":[{"id":..}]}，或 Ollama 风格的 {"models":[{"name":..}]}
+fn model_ids(body: &serde_json::Value) -> Option<Vec<String>> {
+    let (list, key) = match (body.get("data"), body.get("models")) {
+        (Some(data), _) => (data.as_array()?, "id"),
+        (None, Some(models)) => (models.as_array()?, "name"),
+        (None, None) => return None,
+    };
+    Some(list.iter().filter_map(|m| m.get(key)?.as_str().map(str::to_string)).collect())
+}
@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+
+use crate::agent::{TaskEntry, TaskState};
+
+/// 进程内的任务登记表：运行中的任务以及最近结束的任务 (最多保留 history_limit 条)
+#[derive(Debug, Default)]
+pub struct TaskRegistry {
+    next_id: AtomicU64,
+    history_limit: usize,
+    state: RwLock<RegistryState>,
+}
+
+#[derive(Debug, Default)]
+struct RegistryState {
+    running: BTreeMap<u64, TaskEntry>,
+    finished: VecDeque<TaskEntry>,
+}
+
+impl TaskRegistry {
+    pub fn new(history_limit: usize) -> Self {
+        Self { history_limit, ..Default::default() }
+    }
+
+    /// 登记一个开始运行的任务；返回的 guard 被 drop 时任务移入历史记录
+    pub fn start(self: &Arc<Self>, session_id: &str, client_addr: String, model: String) -> TaskGuard {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = TaskEntry {
+            session_id: session_id.to_string(),
+            state: TaskState::Running as i32,
+            started_at: Some(crate::now_timestamp()),
+            client_addr,
+            model,
+            queue_position: -1,
+            ended_at: None,
+        };
+        if let Ok(mut state) = self.state.write() {
+            state.running.insert(id, entry);
+        }
+        TaskGuard { registry: self.clone(), id, final_state: TaskState::Failed }
+    }
+
+    /// 运行中的任务在前 (按开始顺序)，随后是最近结束的任务 (最新的在前)
+    pub fn snapshot(&self) -> Vec<TaskEntry> {
+        let Ok(state) = self.state.read() else { return Vec::new() };
+        state.running.values().chain(state.finished.iter().rev()).cloned().collect()
+    }
+
+    pub fn running_count(&self) -> usize {
+        self.state.read().map_or(0, |state| state.running.len())
+    }
+
+    fn finish(&self, id: u64, final_state: TaskState) {
+        let Ok(mut state) = self.state.write() else { return };
+        let Some(mut entry) = state.running.remove(&id) else { return };
+        entry.state = final_state as i32;
+        entry.ended_at = Some(crate::now_timestamp());
+        state.finished.push_back(entry);
+        while state.finished.len() > self.history_limit {
+            state.finished.pop_front();
+        }
+    }
+}
+
+/// 任务结束 (包括 panic) 时自动登记最终状态，默认为失败
+pub struct TaskGuard {
+    registry: Arc<TaskRegistry>,
+    id: u64,
+    final_state: TaskState,
+}
+
+impl TaskGuard {
+    pub fn set_final_state(&mut self, state: TaskState) {
+        self.final_state = state;
+    }
+}
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        self.registry.finish(self.id, self.final_state);
+    }
+}
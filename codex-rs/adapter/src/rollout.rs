@@ -0,0 +1,152 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, TimeZone, Utc};
+use regex::bytes::Regex;
+
+use crate::error::AdapterError;
+
+const REDACTED: &[u8] = b"[REDACTED]";
+
+/// 按客户端提供的正则逐行脱敏 rollout，避免敏感信息落盘或回传
+#[derive(Debug, Default, Clone)]
+pub struct RolloutRedactor {
+    patterns: Vec<Regex>,
+}
+
+impl RolloutRedactor {
+    pub fn compile(patterns: &[String]) -> Result<Self, AdapterError> {
+        let patterns = patterns
+            .iter()
+            .map(|p| Regex::new(p).map_err(|e| AdapterError::InvalidArgument(format!("invalid rollout_line_filters pattern {p:?}: {e}"))))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// 返回脱敏后的数据以及发生替换的行数
+    pub fn redact(&self, data: &[u8]) -> (Vec<u8>, usize) {
+        if self.patterns.is_empty() {
+            return (data.to_vec(), 0);
+        }
+        let mut out = Vec::with_capacity(data.len());
+        let mut redacted_lines = 0;
+        for (idx, line) in data.split(|b| *b == b'\n').enumerate() {
+            if idx > 0 { out.push(b'\n'); }
+            let mut current = line.to_vec();
+            let mut changed = false;
+            for re in &self.patterns {
+                if re.is_match(&current) {
+                    current = re.replace_all(&current, REDACTED).into_owned();
+                    changed = true;
+                }
+            }
+            if changed { redacted_lines += 1; }
+            out.extend_from_slice(&current);
+        }
+        (out, redacted_lines)
+    }
+}
+
+/// 仅保留最近 `keep_last_n_turns` 轮对话 (response_item / event_msg)，
+/// session_meta、turn_context、compacted 与 token_count 等非对话行始终保留。
+/// 首条用户消息之前的对话行 (初始指令、环境上下文) 视为前言，同样保留。
+/// 返回压缩后的数据以及被移除的轮数。
+pub fn compact(data: &[u8], keep_last_n_turns: usize) -> (Vec<u8>, usize) {
+    let lines: Vec<&[u8]> = data.split(|b| *b == b'\n').filter(|l| !l.is_empty()).collect();
+    let parsed: Vec<Option<serde_json::Value>> = lines.iter().map(|l| serde_json::from_slice(l).ok()).collect();
+
+    let total_turns = parsed.iter().filter(|v| v.as_ref().is_some_and(is_turn_boundary)).count();
+    let turns_removed = total_turns.saturating_sub(keep_last_n_turns);
+    if turns_removed == 0 {
+        return (data.to_vec(), 0);
+    }
+
+    let mut out = Vec::with_capacity(data.len());
+    let mut turn = 0;
+    for (line, value) in lines.iter().zip(&parsed) {
+        let keep = match value {
+            Some(v) => {
+                if is_turn_boundary(v) { turn += 1; }
+                !is_conversation_line(v) || turn == 0 || turn > turns_removed
+            }
+            // 无法解析的行原样保留，由 codex 自行处理
+            None => true,
+        };
+        if keep {
+            out.extend_from_slice(line);
+            out.push(b'\n');
+        }
+    }
+    (out, turns_removed)
+}
+
+fn is_turn_boundary(line: &serde_json::Value) -> bool {
+    line["type"] == "response_item" && line["payload"]["type"] == "message" && line["payload"]["role"] == "user"
+}
+
+fn is_conversation_line(line: &serde_json::Value) -> bool {
+    match line["type"].as_str() {
+        Some("response_item") => true,
+        Some("event_msg") => line["payload"]["type"] != "token_count",
+        _ => false,
+    }
+}
+
+/// 通过校验的 history_rollout 摘要
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RolloutInfo {
+    /// 首条记录 (session_meta) 中的会话 id
+    pub session_id: String,
+    /// 非空行数
+    pub lines: usize,
+}
+
+/// 校验客户端上传的 rollout：非空 UTF-8、每个非空行都是 JSON，且首条记录携带会话 id。
+/// 错误信息中的行号从 1 开始
+pub fn validate_rollout(bytes: &[u8]) -> Result<RolloutInfo, AdapterError> {
+    let invalid = |msg: String| AdapterError::InvalidArgument(format!("history_rollout {msg}"));
+    if bytes.iter().all(u8::is_ascii_whitespace) {
+        return Err(invalid("is empty".to_string()));
+    }
+    let mut session_id = None;
+    let mut lines = 0;
+    for (idx, line) in bytes.split(|b| *b == b'\n').enumerate() {
+        let line_no = idx + 1;
+        let text = std::str::from_utf8(line).map_err(|e| invalid(format!("line {line_no}: invalid UTF-8: {e}")))?;
+        if text.trim().is_empty() { continue; }
+        let record: serde_json::Value = serde_json::from_str(text).map_err(|e| invalid(format!("line {line_no}: {e}")))?;
+        if lines == 0 {
+            let payload = &record["payload"];
+            let id = payload["id"].as_str().or_else(|| payload["session_id"].as_str()).filter(|id| !id.is_empty());
+            let Some(id) = id else {
+                return Err(invalid(format!("line {line_no}: first record carries no session id (expected payload.id)")));
+            };
+            session_id = Some(id.to_string());
+        }
+        lines += 1;
+    }
+    Ok(RolloutInfo { session_id: session_id.unwrap_or_default(), lines })
+}
+
+/// 复活会话时 history_rollout 在 codex home 下的相对路径，按 codex 自身的命名：
+/// `sessions/YYYY/MM/DD/rollout-YYYY-MM-DDThh-mm-ss-<session_id>.jsonl`。
+/// 日期取自首条记录的时间戳 (payload.timestamp 或记录的 timestamp)，缺失或无法解析时使用 now。
+/// codex 按本地时间命名目录与文件，日期与时刻均换算到 now 的时区 (调用方传入 `Local::now()`)
+pub fn revival_path<Tz: TimeZone>(history: &[u8], session_id: &str, now: DateTime<Tz>) -> PathBuf
+where
+    Tz::Offset: std::fmt::Display,
+{
+    let created = first_record_timestamp(history).map_or(now.clone(), |t| t.with_timezone(&now.timezone()));
+    PathBuf::from(created.format("sessions/%Y/%m/%d").to_string())
+        .join(format!("rollout-{}-{session_id}.jsonl", created.format("%Y-%m-%dT%H-%M-%S")))
+}
+
+fn first_record_timestamp(history: &[u8]) -> Option<DateTime<Utc>> {
+    let line = history.split(|b| *b == b'\n').find(|line| !line.iter().all(u8::is_ascii_whitespace))?;
+    let record: serde_json::Value = serde_json::from_slice(line).ok()?;
+    let timestamp = record["payload"]["timestamp"].as_str().or_else(|| record["timestamp"].as_str())?;
+    DateTime::parse_from_rfc3339(timestamp).ok().map(|t| t.with_timezone(&Utc))
+}
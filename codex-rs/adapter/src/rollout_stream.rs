@@ -0,0 +1,120 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, warn};
+
+use crate::agent::RolloutDelta;
+use crate::agent::run_task_response::Event;
+use crate::rollout::RolloutRedactor;
+use crate::{EventSender, event_response};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// stream_rollout：codex 运行期间轮询 codex home 下的 rollout 文件，将新追加的完整行以 RolloutDelta 发出。
+/// 启动时已存在的内容 (复活会话写入的历史) 不重复发送；找到本次会话的文件后只轮询该文件；drop 时停止轮询
+pub struct RolloutStreamer {
+    stop: CancellationToken,
+    task: Mutex<Option<tokio::task::JoinHandle<()>>>,
+}
+
+impl RolloutStreamer {
+    /// 须在启动 codex 之前调用，以便记录已有 rollout 文件的大小
+    pub async fn spawn(codex_home: &Path, redactor: RolloutRedactor, tx: EventSender) -> Self {
+        let root = codex_home.join("sessions");
+        let baseline = scan_file_sizes(&root).await;
+        let stop = CancellationToken::new();
+        let task = tokio::spawn(poll(root, baseline, redactor, tx, stop.clone()));
+        Self { stop, task: Mutex::new(Some(task)) }
+    }
+
+    /// codex 退出后调用：读完最后追加的内容再停止，保证 delta 先于 UpdatedRollout 发出
+    pub async fn finish(&self) {
+        self.stop.cancel();
+        let task = self.task.lock().ok().and_then(|mut task| task.take());
+        if let Some(task) = task {
+            let _ = task.await;
+        }
+    }
+}
+
+impl Drop for RolloutStreamer {
+    fn drop(&mut self) {
+        if let Ok(task) = self.task.get_mut()
+            && let Some(task) = task
+        {
+            task.abort();
+        }
+    }
+}
+
+// 正在跟踪的 rollout 文件及已发送到的偏移
+struct Tracked {
+    path: PathBuf,
+    offset: u64,
+}
+
+async fn poll(root: PathBuf, baseline: HashMap<PathBuf, u64>, redactor: RolloutRedactor, tx: EventSender, stop: CancellationToken) {
+    let mut tracked: Option<Tracked> = None;
+    loop {
+        let stopping = tokio::select! {
+            _ = tokio::time::sleep(POLL_INTERVAL) => false,
+            _ = stop.cancelled() => true,
+        };
+        if tracked.is_none() {
+            // 第一个在启动后增长的文件即为本次会话的 rollout
+            tracked = scan_file_sizes(&root)
+                .await
+                .into_iter()
+                .find(|(path, len)| *len > baseline.get(path).copied().unwrap_or(0))
+                .map(|(path, _)| Tracked { offset: baseline.get(&path).copied().unwrap_or(0), path });
+        }
+        if let Some(t) = &mut tracked
+            && let Err(e) = send_appended(t, &redactor, &tx).await
+        {
+            warn!(path = %t.path.display(), "Failed to stream rollout delta: {e}");
+        }
+        if stopping || tx.is_closed() { return; }
+    }
+}
+
+// 发送 offset 之后新增的完整行；末尾未写完的行留到下一次
+async fn send_appended(tracked: &mut Tracked, redactor: &RolloutRedactor, tx: &EventSender) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::open(&tracked.path).await?;
+    file.seek(std::io::SeekFrom::Start(tracked.offset)).await?;
+    let mut appended = Vec::new();
+    file.read_to_end(&mut appended).await?;
+    let Some(end) = appended.iter().rposition(|b| *b == b'\n') else { return Ok(()) };
+    appended.truncate(end + 1);
+
+    let offset = tracked.offset;
+    tracked.offset += appended.len() as u64;
+    let data = if redactor.is_empty() { appended } else { redactor.redact(&appended).0 };
+    debug!(path = %tracked.path.display(), offset, bytes = data.len(), "Streaming rollout delta");
+    let delta = RolloutDelta {
+        data,
+        offset,
+        file_name: tracked.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default(),
+    };
+    let _ = tx.send(Ok(event_response(Event::RolloutDelta(delta)))).await;
+    Ok(())
+}
+
+// 目录遍历为同步 IO，放到阻塞线程池中执行
+async fn scan_file_sizes(root: &Path) -> HashMap<PathBuf, u64> {
+    let root = root.to_path_buf();
+    tokio::task::spawn_blocking(move || file_sizes(&root)).await.unwrap_or_default()
+}
+
+fn file_sizes(root: &Path) -> HashMap<PathBuf, u64> {
+    walkdir::WalkDir::new(root)
+        .follow_links(false)
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|e| e.file_type().is_file() && e.path().extension().is_some_and(|ext| ext == "jsonl"))
+        .filter_map(|e| Some((e.path().to_path_buf(), e.metadata().ok()?.len())))
+        .collect()
+}
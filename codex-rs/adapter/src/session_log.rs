@@ -0,0 +1,47 @@
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::error::AdapterError;
+
+/// AGENT_LOG_DIR 下按会话落盘的事件日志，供客户端未保存事件时事后排查。
+/// 行经由通道交给独立的阻塞写入任务 (LineWriter 按行落盘)，事件循环不等待磁盘 IO；
+/// 任务出错或被终止时已送出的行在写入任务退出前全部写完
+#[derive(Debug, Clone)]
+pub struct SessionLog {
+    path: PathBuf,
+    lines: mpsc::UnboundedSender<String>,
+}
+
+impl SessionLog {
+    /// 打开 `<dir>/<session_id>.log` (追加写入)；session_id 已由调用方转为安全的文件名
+    pub fn open(dir: &Path, file_stem: &str) -> Result<Self, AdapterError> {
+        std::fs::create_dir_all(dir)?;
+        let path = dir.join(format!("{file_stem}.log"));
+        let file = std::fs::OpenOptions::new().create(true).append(true).open(&path)?;
+        let (lines, mut rx) = mpsc::unbounded_channel::<String>();
+        let log_path = path.clone();
+        // 所有 SessionLog 副本 drop 后通道关闭，写入任务随之退出
+        tokio::task::spawn_blocking(move || {
+            let mut writer = LineWriter::new(file);
+            while let Some(line) = rx.blocking_recv() {
+                if let Err(e) = writer.write_all(line.as_bytes()) {
+                    warn!(path = %log_path.display(), "Failed to write session log: {e}");
+                }
+            }
+        });
+        Ok(Self { path, lines })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// 写入一行 `<RFC 3339 时间> <stream> <line>`；不阻塞调用方，写入失败只记录告警，不影响任务
+    pub fn write(&self, stream: &str, line: &str) {
+        let timestamp = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+        let _ = self.lines.send(format!("{timestamp} {stream} {line}\n"));
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::Instant;
+
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::PrometheusBuilder;
+
+const IN_FLIGHT_TASKS: &str = "adapter_in_flight_tasks";
+
+/// 在 METRICS_ADDR 上启动 Prometheus 导出端 (GET /metrics)；地址为空时不启动
+pub fn install_exporter(addr: &str) -> Result<(), String> {
+    if addr.trim().is_empty() { return Ok(()); }
+    let addr: std::net::SocketAddr = addr.trim().parse().map_err(|e| format!("invalid METRICS_ADDR {addr:?}: {e}"))?;
+    PrometheusBuilder::new()
+        .with_http_listener(addr)
+        .install()
+        .map_err(|e| format!("failed to start metrics exporter on {addr}: {e}"))?;
+    tracing::info!("Prometheus metrics exposed on http://{addr}/metrics");
+    Ok(())
+}
+
+/// RunTask 请求计数；outcome 为 accepted 或拒绝原因
+pub fn record_request(outcome: &'static str) {
+    counter!("adapter_run_task_requests_total", "outcome" => outcome).increment(1);
+}
+
+/// codex 退出计数，按退出码分组
+pub fn record_codex_exit(exit_code: i32) {
+    counter!("adapter_codex_exits_total", "exit_code" => exit_code.to_string()).increment(1);
+}
+
+/// 任务在途期间计入 in-flight gauge；drop (含取消与 panic) 时减一并记录任务时长
+pub struct InFlightTask {
+    started: Instant,
+}
+
+impl InFlightTask {
+    pub fn start() -> Self {
+        gauge!(IN_FLIGHT_TASKS).increment(1.0);
+        Self { started: Instant::now() }
+    }
+}
+
+impl Drop for InFlightTask {
+    fn drop(&mut self) {
+        gauge!(IN_FLIGHT_TASKS).decrement(1.0);
+        histogram!("adapter_task_duration_seconds").record(self.started.elapsed().as_secs_f64());
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::{EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+use tracing::warn;
+
+use crate::agent::WorkspaceFileEvent;
+use crate::agent::run_task_response::Event;
+use crate::error::AdapterError;
+use crate::{EventSender, event_response};
+
+// 合并窗口：编辑器式的临时文件与连续写入在窗口内只上报一次
+const DEBOUNCE_WINDOW: Duration = Duration::from_millis(100);
+
+/// 任务运行期间监视工作区的文件创建与修改；drop 时停止监视
+pub struct WorkspaceWatcher {
+    _watcher: notify::RecommendedWatcher,
+    forwarder: tokio::task::JoinHandle<()>,
+}
+
+impl WorkspaceWatcher {
+    pub fn spawn(work_dir: &Path, tx: EventSender) -> Result<Self, AdapterError> {
+        let (raw_tx, raw_rx) = mpsc::unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |res: notify::Result<notify::Event>| match res {
+            Ok(event) => {
+                let _ = raw_tx.send(event);
+            }
+            Err(e) => warn!("Workspace watcher error: {e}"),
+        })
+        .map_err(anyhow::Error::from)?;
+        watcher.watch(work_dir, RecursiveMode::Recursive).map_err(anyhow::Error::from)?;
+        let forwarder = tokio::spawn(forward_debounced(work_dir.to_path_buf(), raw_rx, tx));
+        Ok(Self { _watcher: watcher, forwarder })
+    }
+}
+
+impl Drop for WorkspaceWatcher {
+    fn drop(&mut self) {
+        self.forwarder.abort();
+    }
+}
+
+async fn forward_debounced(work_dir: PathBuf, mut raw_rx: mpsc::UnboundedReceiver<notify::Event>, tx: EventSender) {
+    while let Some(first) = raw_rx.recv().await {
+        // 同一路径在窗口内的多次事件只保留最后一次的类型
+        let mut pending = BTreeMap::new();
+        record(&mut pending, first);
+        let window = tokio::time::sleep(DEBOUNCE_WINDOW);
+        tokio::pin!(window);
+        loop {
+            tokio::select! {
+                _ = &mut window => break,
+                next = raw_rx.recv() => match next {
+                    Some(event) => record(&mut pending, event),
+                    None => break,
+                },
+            }
+        }
+
+        for (path, event_type) in pending {
+            // 窗口结束前已被删除的文件 (多为临时文件) 不再上报
+            let Ok(metadata) = tokio::fs::metadata(&path).await else { continue };
+            if !metadata.is_file() { continue; }
+            let relative = path.strip_prefix(&work_dir).unwrap_or(&path);
+            let event = WorkspaceFileEvent {
+                event_type: event_type.to_string(),
+                path: relative.display().to_string(),
+                size_bytes: metadata.len(),
+            };
+            if tx.send(Ok(event_response(Event::WorkspaceFileEvent(event)))).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+fn record(pending: &mut BTreeMap<PathBuf, &'static str>, event: notify::Event) {
+    let event_type = match event.kind {
+        EventKind::Create(_) => "create",
+        EventKind::Modify(_) => "modify",
+        _ => return,
+    };
+    for path in event.paths {
+        // 窗口内先创建后修改的文件仍按创建上报
+        let entry = pending.entry(path).or_insert(event_type);
+        if *entry != "create" { *entry = event_type; }
+    }
+}
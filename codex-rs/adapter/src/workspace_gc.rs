@@ -0,0 +1,149 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::error::{AdapterError, write_file};
+
+// 持久化工作区的元数据，供 GC 判断是否过期
+pub const METADATA_FILE_NAME: &str = ".codex-metadata.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct WorkspaceMetadata {
+    session_id: String,
+    /// 最近一次任务开始或结束的时间 (Unix 秒)
+    last_task_time: i64,
+    ttl_secs: u64,
+}
+
+// 设置了 TTL 的持久化工作区 -> 当前正在使用它的任务数
+static PERSISTENT_WORKSPACES: LazyLock<Mutex<HashMap<PathBuf, usize>>> = LazyLock::new(Default::default);
+
+/// 任务运行期间持有，防止 GC 删除正在使用的工作区
+pub struct WorkspaceLease {
+    path: PathBuf,
+    session_id: String,
+    ttl_secs: u64,
+}
+
+impl WorkspaceLease {
+    pub async fn acquire(path: &Path, session_id: &str, ttl_secs: u64) -> Result<Self, AdapterError> {
+        if let Ok(mut workspaces) = PERSISTENT_WORKSPACES.lock() {
+            *workspaces.entry(path.to_path_buf()).or_default() += 1;
+        }
+        let lease = Self { path: path.to_path_buf(), session_id: session_id.to_string(), ttl_secs };
+        lease.touch().await?;
+        Ok(lease)
+    }
+
+    /// 刷新 last_task_time
+    pub async fn touch(&self) -> Result<(), AdapterError> {
+        let metadata = WorkspaceMetadata {
+            session_id: self.session_id.clone(),
+            last_task_time: chrono::Utc::now().timestamp(),
+            ttl_secs: self.ttl_secs,
+        };
+        let bytes = serde_json::to_vec_pretty(&metadata).map_err(anyhow::Error::from)?;
+        write_file(&self.path.join(METADATA_FILE_NAME), bytes).await
+    }
+}
+
+impl Drop for WorkspaceLease {
+    fn drop(&mut self) {
+        if let Ok(mut workspaces) = PERSISTENT_WORKSPACES.lock()
+            && let Some(count) = workspaces.get_mut(&self.path)
+        {
+            *count = count.saturating_sub(1);
+        }
+    }
+}
+
+/// workspace_persistence_ttl_secs 只允许用于持久化根目录之下的 base_dir，返回规范化后的路径
+pub fn persistent_workspace_path(root: Option<&Path>, base_dir: &Path) -> Result<PathBuf, AdapterError> {
+    let Some(root) = root else {
+        return Err(AdapterError::InvalidArgument("workspace_persistence_ttl_secs requires PERSISTENT_WORKSPACE_ROOT to be set on the server".to_string()));
+    };
+    let root = std::fs::canonicalize(root).map_err(|e| anyhow::anyhow!("PERSISTENT_WORKSPACE_ROOT {} is not accessible: {e}", root.display()))?;
+    let path = std::fs::canonicalize(base_dir)
+        .map_err(|e| AdapterError::InvalidArgument(format!("base_dir {} is not accessible: {e}", base_dir.display())))?;
+    if path == root || !path.starts_with(&root) {
+        return Err(AdapterError::InvalidArgument(format!(
+            "base_dir {} must be inside PERSISTENT_WORKSPACE_ROOT ({}) to use workspace_persistence_ttl_secs",
+            base_dir.display(),
+            root.display()
+        )));
+    }
+    Ok(path)
+}
+
+/// 未设置持久化根目录时不存在可回收的工作区，不启动 GC
+pub fn spawn_gc_worker(interval: Duration, root: Option<PathBuf>) {
+    let Some(root) = root.and_then(|root| std::fs::canonicalize(&root).inspect_err(|e| warn!(root = %root.display(), "Persistent workspace root is not accessible, workspace GC disabled: {e}")).ok()) else {
+        return;
+    };
+    tokio::spawn(async move {
+        // 登记表只在内存中，重启后从根目录下的元数据文件恢复
+        let scan_root = root.clone();
+        match tokio::task::spawn_blocking(move || scan_persistent_workspaces(&scan_root)).await {
+            Ok(found) => {
+                info!(root = %root.display(), workspaces = found.len(), "Registered persistent workspaces for GC");
+                if let Ok(mut workspaces) = PERSISTENT_WORKSPACES.lock() {
+                    for path in found { workspaces.entry(path).or_default(); }
+                }
+            }
+            Err(e) => warn!("Failed to scan persistent workspaces: {e}"),
+        }
+        loop {
+            tokio::time::sleep(interval).await;
+            sweep_expired_workspaces(&root).await;
+        }
+    });
+}
+
+/// 根目录下所有带元数据文件的工作区；已识别的工作区不再向下遍历
+pub fn scan_persistent_workspaces(root: &Path) -> Vec<PathBuf> {
+    let mut found = Vec::new();
+    let mut entries = walkdir::WalkDir::new(root).min_depth(1).follow_links(false).into_iter();
+    while let Some(entry) = entries.next() {
+        let Ok(entry) = entry else { continue };
+        if !entry.file_type().is_dir() { continue; }
+        if entry.path().join(METADATA_FILE_NAME).is_file() {
+            found.push(entry.path().to_path_buf());
+            entries.skip_current_dir();
+        }
+    }
+    found
+}
+
+async fn sweep_expired_workspaces(root: &Path) {
+    let idle: Vec<PathBuf> = match PERSISTENT_WORKSPACES.lock() {
+        Ok(workspaces) => workspaces.iter().filter(|(_, count)| **count == 0).map(|(p, _)| p.clone()).collect(),
+        Err(_) => return,
+    };
+    let now = chrono::Utc::now().timestamp();
+    for path in idle {
+        let Ok(raw) = tokio::fs::read(path.join(METADATA_FILE_NAME)).await else { continue };
+        let Ok(metadata) = serde_json::from_slice::<WorkspaceMetadata>(&raw) else { continue };
+        let expires_at = metadata.last_task_time.saturating_add(metadata.ttl_secs as i64);
+        if metadata.ttl_secs == 0 || now < expires_at { continue; }
+
+        // 删除前再次确认没有任务在此期间重新占用该工作区
+        let still_idle = PERSISTENT_WORKSPACES.lock().is_ok_and(|mut workspaces| {
+            let idle = workspaces.get(&path) == Some(&0);
+            if idle { workspaces.remove(&path); }
+            idle
+        });
+        if !still_idle { continue; }
+        if !path.starts_with(root) || path == root {
+            warn!(path = %path.display(), "Refusing to remove a workspace outside the persistent workspace root");
+            continue;
+        }
+        match tokio::fs::remove_dir_all(&path).await {
+            Ok(()) => info!(path = %path.display(), session_id = %metadata.session_id, "Removed expired persistent workspace"),
+            Err(e) => warn!(path = %path.display(), "Failed to remove expired workspace: {e}"),
+        }
+    }
+}